@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::server::{MatchRecord, Rating};
+
+// 初めて対戦する人のレーティング
+const INITIAL_RATING: f64 = 1500.;
+// 1回の対戦で動くレーティングの大きさ
+const K_FACTOR: f64 = 32.;
+// 残しておく対戦の数 (古いものから消す)
+const MAX_MATCHES: usize = 1000;
+
+/// 終わったVSの対戦とプレイヤーごとのレーティング (ファイルに保存する)
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Ladder {
+    players: HashMap<String, Rating>,
+    // 古い順
+    matches: Vec<MatchRecord>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Ladder {
+    /// ファイルがなければ空から始める
+    pub fn load(path: impl Into<PathBuf>) -> io::Result<Ladder> {
+        let path = path.into();
+        let mut ladder = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Ladder::default(),
+            Err(e) => return Err(e),
+        };
+        ladder.path = path;
+        Ok(ladder)
+    }
+
+    // 書きかけのファイルが残らないよう、別のファイルに書いてから置き換える
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(tmp, &self.path)
+    }
+
+    /// 対戦を記録して、2人のレーティングを変える
+    /// 保存できなかったときは何も変えない
+    pub fn record(&mut self, record: MatchRecord) -> io::Result<()> {
+        let mut next = self.clone();
+        next.apply(record);
+        next.save()?;
+        *self = next;
+        Ok(())
+    }
+
+    fn apply(&mut self, record: MatchRecord) {
+        let host = self.rating(&record.host);
+        let guest = self.rating(&record.guest);
+
+        // ホストから見た結果
+        let score = match &record.winner {
+            Some(winner) if *winner == record.host => 1.,
+            Some(_) => 0.,
+            None => 0.5,
+        };
+        let expected = 1. / (1. + 10f64.powf((guest.rating - host.rating) / 400.));
+        let change = K_FACTOR * (score - expected);
+
+        self.players
+            .insert(record.host.clone(), updated(host, change, score));
+        self.players
+            .insert(record.guest.clone(), updated(guest, -change, 1. - score));
+        self.matches.push(record);
+        if self.matches.len() > MAX_MATCHES {
+            let over = self.matches.len() - MAX_MATCHES;
+            self.matches.drain(..over);
+        }
+    }
+
+    /// 対戦したことがなければ初めのレーティング
+    pub fn rating(&self, name: &str) -> Rating {
+        self.players.get(name).cloned().unwrap_or_else(|| Rating {
+            name: name.to_string(),
+            rating: INITIAL_RATING,
+            wins: 0,
+            losses: 0,
+            draws: 0,
+        })
+    }
+
+    /// レーティングが高い順
+    pub fn leaderboard(&self, limit: usize) -> Vec<Rating> {
+        let mut list: Vec<Rating> = self.players.values().cloned().collect();
+        list.sort_by(|a, b| b.rating.total_cmp(&a.rating));
+        list.truncate(limit);
+        list
+    }
+
+    /// その人の対戦 (新しい順)
+    pub fn history(&self, name: &str, limit: usize) -> Vec<MatchRecord> {
+        self.matches
+            .iter()
+            .rev()
+            .filter(|record| record.host == name || record.guest == name)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+// 結果 (勝ち1、引き分け0.5、負け0) を反映したレーティング
+fn updated(mut rating: Rating, change: f64, score: f64) -> Rating {
+    rating.rating += change;
+    match score {
+        s if s > 0.5 => rating.wins += 1,
+        s if s < 0.5 => rating.losses += 1,
+        _ => rating.draws += 1,
+    }
+    rating
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    // テストごとに別のファイル (終わったら消す)
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> TempPath {
+            let path = std::env::temp_dir().join(format!(
+                "invader-ladder-{}-{}.json",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_file(&path);
+            TempPath(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            let _ = fs::remove_file(self.0.with_extension("tmp"));
+        }
+    }
+
+    fn record(host: &str, guest: &str, winner: Option<&str>) -> MatchRecord {
+        MatchRecord {
+            host: host.to_string(),
+            guest: guest.to_string(),
+            winner: winner.map(str::to_string),
+            duration: 60,
+            host_hp: 10,
+            guest_hp: 0,
+            finished: 0,
+        }
+    }
+
+    fn assert_rating(
+        ladder: &Ladder,
+        name: &str,
+        rating: f64,
+        (wins, losses, draws): (u32, u32, u32),
+    ) {
+        let actual = ladder.rating(name);
+        assert!(
+            (actual.rating - rating).abs() < 1e-6,
+            "{}: {} != {}",
+            name,
+            actual.rating,
+            rating
+        );
+        assert_eq!(
+            (actual.wins, actual.losses, actual.draws),
+            (wins, losses, draws)
+        );
+    }
+
+    fn ladder(path: &Path) -> Ladder {
+        Ladder::load(path).unwrap()
+    }
+
+    #[test]
+    fn elo_win_and_loss() {
+        let path = TempPath::new("elo");
+        let mut ladder = ladder(&path.0);
+        assert_rating(&ladder, "a", INITIAL_RATING, (0, 0, 0));
+
+        // 同じレーティングなら K/2 動く
+        ladder.record(record("a", "b", Some("a"))).unwrap();
+        assert_rating(&ladder, "a", 1516., (1, 0, 0));
+        assert_rating(&ladder, "b", 1484., (0, 1, 0));
+
+        // 低い方が勝つと大きく動く
+        ladder.record(record("a", "b", Some("b"))).unwrap();
+        let change = K_FACTOR * (1. / (1. + 10f64.powf(-32. / 400.)));
+        assert!((change - 17.469_501_5).abs() < 1e-6);
+        assert_rating(&ladder, "a", 1516. - change, (1, 1, 0));
+        assert_rating(&ladder, "b", 1484. + change, (1, 1, 0));
+    }
+
+    #[test]
+    fn elo_draw() {
+        let path = TempPath::new("draw");
+        let mut ladder = ladder(&path.0);
+
+        // 同じレーティングの引き分けは動かない
+        ladder.record(record("a", "b", None)).unwrap();
+        assert_rating(&ladder, "a", 1500., (0, 0, 1));
+        assert_rating(&ladder, "b", 1500., (0, 0, 1));
+
+        // 高い方には引き分けでも少し下がる
+        ladder.record(record("a", "c", Some("a"))).unwrap();
+        ladder.record(record("c", "a", None)).unwrap();
+        let change = 1.469_501_5;
+        assert_rating(&ladder, "a", 1516. - change, (1, 0, 2));
+        assert_rating(&ladder, "c", 1484. + change, (0, 1, 1));
+    }
+
+    #[test]
+    fn forfeit_counts_as_win() {
+        let path = TempPath::new("forfeit");
+        let mut ladder = ladder(&path.0);
+
+        // 相手が抜けたときはHPに関係なく残った方の勝ち
+        let forfeit = MatchRecord {
+            host_hp: 5,
+            guest_hp: 40,
+            ..record("a", "b", Some("a"))
+        };
+        ladder.record(forfeit).unwrap();
+        assert_rating(&ladder, "a", 1516., (1, 0, 0));
+        assert_rating(&ladder, "b", 1484., (0, 1, 0));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = TempPath::new("save");
+        let mut ladder = ladder(&path.0);
+        ladder.record(record("a", "b", Some("a"))).unwrap();
+        ladder.record(record("b", "a", None)).unwrap();
+
+        // 置き換えたあとは書きかけのファイルが残らない
+        assert!(path.0.exists());
+        assert!(!path.0.with_extension("tmp").exists());
+
+        let loaded = Ladder::load(&path.0).unwrap();
+        for name in ["a", "b"] {
+            let (saved, loaded) = (ladder.rating(name), loaded.rating(name));
+            assert_eq!(saved.rating, loaded.rating);
+            assert_eq!(
+                (saved.wins, saved.losses, saved.draws),
+                (loaded.wins, loaded.losses, loaded.draws)
+            );
+        }
+        let history = loaded.history("a", 10);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].host, "b");
+        assert_eq!(loaded.leaderboard(1)[0].name, "a");
+
+        // 読み込んだものに続けて記録しても同じファイルに書く
+        let mut loaded = loaded;
+        loaded.record(record("a", "c", Some("c"))).unwrap();
+        assert_eq!(Ladder::load(&path.0).unwrap().history("c", 10).len(), 1);
+    }
+
+    #[test]
+    fn failed_save_changes_nothing() {
+        let path = TempPath::new("failed");
+        let mut ladder = ladder(&path.0);
+        ladder.record(record("a", "b", Some("a"))).unwrap();
+
+        // 置き換え先がディレクトリだと保存できない
+        fs::remove_file(&path.0).unwrap();
+        fs::create_dir(&path.0).unwrap();
+        assert!(ladder.record(record("a", "b", Some("b"))).is_err());
+        fs::remove_dir(&path.0).unwrap();
+
+        assert_rating(&ladder, "a", 1516., (1, 0, 0));
+        assert_rating(&ladder, "b", 1484., (0, 1, 0));
+        assert_eq!(ladder.history("a", 10).len(), 1);
+    }
+
+    #[test]
+    fn old_matches_are_dropped() {
+        let path = TempPath::new("cap");
+        let mut ladder = ladder(&path.0);
+        for _ in 0..MAX_MATCHES {
+            ladder.apply(record("a", "b", None));
+        }
+        ladder.record(record("a", "c", Some("c"))).unwrap();
+
+        // レーティングは残るが、一番古い対戦は消える
+        let loaded = Ladder::load(&path.0).unwrap();
+        assert_eq!(loaded.history("a", usize::MAX).len(), MAX_MATCHES);
+        assert_eq!(loaded.history("b", usize::MAX).len(), MAX_MATCHES - 1);
+        assert_eq!(loaded.rating("b").draws, MAX_MATCHES as u32);
+    }
+
+    #[test]
+    fn load_rejects_broken_file() {
+        let path = TempPath::new("broken");
+        fs::write(&path.0, b"{ not json").unwrap();
+        assert!(Ladder::load(&path.0).is_err());
+    }
+}
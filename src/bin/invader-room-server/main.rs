@@ -0,0 +1,777 @@
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::{get, post, web, App, HttpServer, Responder};
+use bincode::Options;
+use serde::Deserialize;
+use socket2::Type;
+
+mod ladder;
+#[path = "../../game/server.rs"]
+#[allow(dead_code)]
+mod server;
+
+use ladder::Ladder;
+use server::{
+    bind_socket, check_reply, Answer, Handshake, Hello, HostToken, MatchInfo, MatchReport,
+    OnlineMode, ResultResponse, RoomInfo, RoomRequest, Rules, SeqWindow, SessionKey, User,
+    WaitResponse, POLL_TIMEOUT, RELAY_HEADER_SIZE,
+};
+
+// IPv4とIPv6の両方で待ち受ける
+const DEFAULT_ADDRESS: &str = "[::]:9999";
+// IPv6が使えないとき
+const FALLBACK_ADDRESS: &str = "0.0.0.0:9999";
+// 対戦の記録とレーティングを保存するファイル
+const DEFAULT_LADDER: &str = "ladder.json";
+// 一覧で返す最大の数
+const DEFAULT_LIMIT: usize = 50;
+// 中継するデータグラムの最大サイズ
+const MAX_RELAY_SIZE: usize = 1024;
+// これより長く何も送ってこない相手は中継先から消す
+const RELAY_EXPIRE: Duration = Duration::from_secs(60);
+// ホストが閉じずにいなくなった対戦を一覧から消すまでの時間
+const MATCH_EXPIRE: Duration = Duration::from_secs(3 * 60 * 60);
+// ホストが待つのをやめる時間 (3分) を過ぎても誰も入っていない部屋を消すまでの時間
+const ROOM_EXPIRE: Duration = Duration::from_secs(5 * 60);
+// 入ってきた相手がホストの返事を待つ時間
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(3);
+
+// ホストに渡して返事を待っている相手
+struct Guest {
+    hello: Hello,
+    answer: Sender<Handshake>,
+}
+
+struct Room {
+    host: User,
+    mode: OnlineMode,
+    rules: Rules,
+    created: SystemTime,
+    // ホストとゲストだけに教える
+    key: SessionKey,
+    // ホストだけに教える
+    token: HostToken,
+    // 最後に記録した試合の番号
+    reported: Option<u32>,
+    // 入った相手の名前と時刻 (ホストが閉じるまで観戦できる)
+    playing: Option<(String, SystemTime)>,
+    // 入ってきた相手はホストが `/wait` で受け取る
+    // (ホストがNATの内側にいてもつなげるように、部屋サーバーからはつながない)
+    guests: Sender<Guest>,
+    waiting: Arc<Mutex<Receiver<Guest>>>,
+    // ホストに渡して `/answer` を待っている相手
+    answering: Option<Guest>,
+}
+
+impl HostToken {
+    fn generate() -> HostToken {
+        HostToken(rand::random())
+    }
+
+    // かかる時間から中身が分からないよう、全てのバイトを比べる
+    fn matches(&self, other: Option<&HostToken>) -> bool {
+        other.is_some_and(|other| {
+            self.0
+                .iter()
+                .zip(other.0)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+        })
+    }
+}
+
+#[derive(Default)]
+struct Rooms(Mutex<HashMap<u32, Room>>);
+
+struct Ratings(Mutex<Ladder>);
+
+#[derive(Deserialize)]
+struct ListQuery {
+    limit: Option<usize>,
+}
+
+impl ListQuery {
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).min(DEFAULT_LIMIT)
+    }
+}
+
+// 部屋を作る
+#[post("/create")]
+async fn create(rooms: web::Data<Rooms>, request: web::Json<RoomRequest>) -> impl Responder {
+    let RoomRequest {
+        room_id,
+        user,
+        mode,
+        rules,
+        ..
+    } = request.into_inner();
+    let mut rooms = rooms.0.lock().unwrap();
+
+    let response = match rooms.entry(room_id) {
+        Entry::Occupied(_) => ResultResponse::Err(format!("room {} already exists", room_id)),
+        Entry::Vacant(entry) => {
+            println!("create {}: {:?}", room_id, user);
+            let key = SessionKey::generate();
+            let token = HostToken::generate();
+            let (guests, waiting) = mpsc::channel();
+            entry.insert(Room {
+                host: user,
+                mode,
+                rules: rules.clone(),
+                created: SystemTime::now(),
+                key,
+                token,
+                reported: None,
+                playing: None,
+                guests,
+                waiting: Arc::new(Mutex::new(waiting)),
+                answering: None,
+            });
+            ResultResponse::Ok {
+                message: format!("created room {}", room_id),
+                user: None,
+                mode,
+                rules,
+                key: Some(key),
+                token: Some(token),
+            }
+        }
+    };
+
+    web::Json(response)
+}
+
+// 部屋に入る
+#[post("/enter")]
+async fn enter(rooms: web::Data<Rooms>, request: web::Json<RoomRequest>) -> impl Responder {
+    let RoomRequest {
+        room_id,
+        user,
+        version,
+        ..
+    } = request.into_inner();
+
+    // 入った部屋は一覧から消して、観戦できる対戦にする
+    let (answer, reply) = mpsc::channel();
+    let (host, mode, rules, key) = match rooms.0.lock().unwrap().get_mut(&room_id) {
+        Some(room) if room.playing.is_none() => {
+            println!("enter {}: {:?}", room_id, user);
+            room.playing = Some((user.name.clone(), SystemTime::now()));
+            let hello = Hello {
+                version,
+                user,
+                key: Some(room.key),
+                tick_rate: None,
+            };
+            // 受け取る側は部屋が持っているので送れないことはない
+            let _ = room.guests.send(Guest { hello, answer });
+            (room.host.clone(), room.mode, room.rules.clone(), room.key)
+        }
+        _ => return web::Json(ResultResponse::Err(format!("room {} not found", room_id))),
+    };
+
+    let replied = web::block(move || reply.recv_timeout(ANSWER_TIMEOUT)).await;
+
+    let response = match replied {
+        Ok(Ok(Handshake::Hello(reply))) => ResultResponse::Ok {
+            message: format!("entered room {}", room_id),
+            user: Some(host),
+            mode,
+            // ティックレートはホストが決めたもの
+            rules: Rules {
+                tick_rate: reply.tick_rate.unwrap_or(rules.tick_rate),
+                ..rules
+            },
+            key: Some(key),
+            token: None,
+        },
+        Ok(Ok(Handshake::Reject(reason))) => {
+            ResultResponse::Err(format!("host rejected: {}", reason))
+        }
+        Ok(Ok(Handshake::Ack)) => ResultResponse::Err("unexpected handshake".to_string()),
+        Ok(Err(_)) => ResultResponse::Err("host did not answer".to_string()),
+        Err(e) => ResultResponse::Err(e.to_string()),
+    };
+    // ホストは失敗しても次の相手を待っているので、部屋は残して一覧に戻す
+    // (ホストがいなくなっていても `ROOM_EXPIRE` が過ぎれば消える)
+    if matches!(response, ResultResponse::Err(_)) {
+        if let Some(room) = rooms.0.lock().unwrap().get_mut(&room_id) {
+            room.playing = None;
+        }
+    }
+
+    web::Json(response)
+}
+
+// 部屋に入ってきた相手をホストに渡す (合言葉を持っているホストだけができる)
+// 誰も来なければ `POLL_TIMEOUT` 待ってから `Empty` を返すので、ホストはまた聞く
+#[post("/wait")]
+async fn wait(rooms: web::Data<Rooms>, request: web::Json<RoomRequest>) -> impl Responder {
+    let RoomRequest { room_id, token, .. } = request.into_inner();
+    let waiting = match rooms.0.lock().unwrap().get(&room_id) {
+        Some(room) if room.token.matches(token.as_ref()) => room.waiting.clone(),
+        _ => return web::Json(WaitResponse::Err(format!("room {} not found", room_id))),
+    };
+
+    let received = web::block(move || waiting.lock().unwrap().recv_timeout(POLL_TIMEOUT)).await;
+
+    let response = match received {
+        Ok(Ok(guest)) => match rooms.0.lock().unwrap().get_mut(&room_id) {
+            Some(room) => {
+                let hello = guest.hello.clone();
+                room.answering = Some(guest);
+                WaitResponse::Guest(hello)
+            }
+            None => WaitResponse::Err(format!("room {} not found", room_id)),
+        },
+        Ok(Err(RecvTimeoutError::Timeout)) => WaitResponse::Empty,
+        // 部屋が閉じられた
+        Ok(Err(RecvTimeoutError::Disconnected)) => {
+            WaitResponse::Err(format!("room {} not found", room_id))
+        }
+        Err(e) => WaitResponse::Err(e.to_string()),
+    };
+
+    web::Json(response)
+}
+
+// ホストの返事を入ってきた相手に渡す
+// 入る側の代わりに返事を確かめて、受け入れられなければ両方に理由を返す
+#[post("/answer")]
+async fn forward_answer(rooms: web::Data<Rooms>, request: web::Json<Answer>) -> impl Responder {
+    let Answer {
+        room_id,
+        token,
+        reply,
+    } = request.into_inner();
+    let (guest, mode, rules) = match rooms.0.lock().unwrap().get_mut(&room_id) {
+        Some(room) if room.token.matches(Some(&token)) => {
+            (room.answering.take(), room.mode, room.rules.clone())
+        }
+        _ => return web::Json(ResultResponse::Err(format!("room {} not found", room_id))),
+    };
+    let Some(guest) = guest else {
+        return web::Json(ResultResponse::Err("no one is entering".to_string()));
+    };
+
+    let checked = match reply {
+        Handshake::Hello(reply) => match check_reply(&guest.hello, &reply) {
+            Ok(()) => Ok(Handshake::Hello(reply)),
+            Err(reason) => Err(reason),
+        },
+        reply => Ok(reply),
+    };
+    let sent = match checked {
+        Ok(reply) => guest.answer.send(reply),
+        Err(reason) => {
+            let _ = guest.answer.send(Handshake::Reject(reason.clone()));
+            return web::Json(ResultResponse::Err(reason));
+        }
+    };
+
+    let response = match sent {
+        Ok(()) => ResultResponse::Ok {
+            message: format!("answered room {}", room_id),
+            user: Some(guest.hello.user),
+            mode,
+            rules,
+            key: None,
+            token: None,
+        },
+        // 待ちきれずに帰った
+        Err(_) => ResultResponse::Err(format!("{} left", guest.hello.user.name)),
+    };
+
+    web::Json(response)
+}
+
+// 部屋を閉じる (合言葉を持っているホストだけができる)
+#[post("/leave")]
+async fn leave(rooms: web::Data<Rooms>, request: web::Json<RoomRequest>) -> impl Responder {
+    let RoomRequest {
+        room_id,
+        user,
+        token,
+        ..
+    } = request.into_inner();
+    let mut rooms = rooms.0.lock().unwrap();
+
+    let response = match rooms.entry(room_id) {
+        Entry::Occupied(entry) if entry.get().token.matches(token.as_ref()) => {
+            println!("leave {}: {:?}", room_id, user);
+            let room = entry.remove();
+            ResultResponse::Ok {
+                message: format!("closed room {}", room_id),
+                user: None,
+                mode: room.mode,
+                rules: room.rules,
+                key: None,
+                token: None,
+            }
+        }
+        _ => ResultResponse::Err(format!("room {} not found", room_id)),
+    };
+
+    web::Json(response)
+}
+
+// 入れる部屋の一覧 (新しい順)
+#[get("/rooms")]
+async fn list(rooms: web::Data<Rooms>) -> impl Responder {
+    let mut rooms = rooms.0.lock().unwrap();
+    rooms.retain(|_, room| {
+        room.playing.is_some() || room.created.elapsed().map_or(true, |e| e < ROOM_EXPIRE)
+    });
+
+    let mut list: Vec<RoomInfo> = rooms
+        .iter()
+        .filter(|(_, room)| room.playing.is_none())
+        .map(|(&room_id, room)| RoomInfo {
+            room_id,
+            host: room.host.name.clone(),
+            mode: room.mode,
+            rules: room.rules.clone(),
+            created: room
+                .created
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        })
+        .collect();
+    list.sort_by_key(|room| Reverse(room.created));
+
+    web::Json(list)
+}
+
+// 観戦できるVSの対戦の一覧 (新しい順)
+#[get("/matches")]
+async fn matches(rooms: web::Data<Rooms>) -> impl Responder {
+    let mut rooms = rooms.0.lock().unwrap();
+    rooms.retain(|_, room| {
+        room.playing
+            .as_ref()
+            .is_none_or(|(_, started)| started.elapsed().map_or(true, |e| e < MATCH_EXPIRE))
+    });
+
+    let mut playing: Vec<MatchInfo> = rooms
+        .iter()
+        .filter(|(_, room)| room.mode == OnlineMode::VS)
+        .filter_map(|(&room_id, room)| {
+            let (guest, started) = room.playing.as_ref()?;
+            Some(MatchInfo {
+                room_id,
+                host: room.host.clone(),
+                guest: guest.clone(),
+                rules: room.rules.clone(),
+                started: started
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+            })
+        })
+        .collect();
+    playing.sort_by_key(|info| Reverse(info.started));
+
+    web::Json(playing)
+}
+
+// 対戦の結果を記録する (観戦できる対戦のホストだけが、1つの対戦につき1回だけできる)
+#[post("/report")]
+async fn report(
+    rooms: web::Data<Rooms>,
+    ratings: web::Data<Ratings>,
+    request: web::Json<MatchReport>,
+) -> impl Responder {
+    let MatchReport {
+        room_id,
+        token,
+        round,
+        mut record,
+    } = request.into_inner();
+
+    let rules = match rooms.0.lock().unwrap().get_mut(&room_id) {
+        Some(Room {
+            host,
+            mode: OnlineMode::VS,
+            rules,
+            token: host_token,
+            reported,
+            playing: Some((guest, _)),
+            ..
+        }) if host_token.matches(Some(&token))
+            && record.host == host.name
+            && record.guest == *guest =>
+        {
+            // 送り直しや古いものを記録し直さない
+            if reported.is_some_and(|last| round <= last) {
+                return web::Json(ResultResponse::Err(format!(
+                    "match {} round {} is already recorded",
+                    room_id, round
+                )));
+            }
+            *reported = Some(round);
+            rules.clone()
+        }
+        _ => return web::Json(ResultResponse::Err(format!("match {} not found", room_id))),
+    };
+
+    // 同じ名前どうしや名前のない人の対戦はレーティングに入れない
+    if record.host.is_empty() || record.guest.is_empty() || record.host == record.guest {
+        return web::Json(ResultResponse::Err(
+            "players need different names".to_string(),
+        ));
+    }
+    if record
+        .winner
+        .as_ref()
+        .is_some_and(|winner| *winner != record.host && *winner != record.guest)
+    {
+        return web::Json(ResultResponse::Err("unknown winner".to_string()));
+    }
+
+    record.finished = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    println!("report {}: {:?}", room_id, record);
+
+    // ファイルに書くのでブロックしてよいスレッドで
+    let recorded = web::block(move || ratings.0.lock().unwrap().record(record)).await;
+
+    let response = match recorded {
+        Ok(Ok(())) => ResultResponse::Ok {
+            message: format!("recorded match {}", room_id),
+            user: None,
+            mode: OnlineMode::VS,
+            rules,
+            key: None,
+            token: None,
+        },
+        Ok(Err(e)) => {
+            eprintln!("ladder: {}", e);
+            ResultResponse::Err(e.to_string())
+        }
+        Err(e) => ResultResponse::Err(e.to_string()),
+    };
+
+    web::Json(response)
+}
+
+// レーティングが高い順
+#[get("/leaderboard")]
+async fn leaderboard(ratings: web::Data<Ratings>, query: web::Query<ListQuery>) -> impl Responder {
+    web::Json(ratings.0.lock().unwrap().leaderboard(query.limit()))
+}
+
+// プレイヤーのレーティング
+#[get("/players/{name}")]
+async fn player(ratings: web::Data<Ratings>, name: web::Path<String>) -> impl Responder {
+    web::Json(ratings.0.lock().unwrap().rating(&name))
+}
+
+// プレイヤーの対戦の記録 (新しい順)
+#[get("/players/{name}/matches")]
+async fn history(
+    ratings: web::Data<Ratings>,
+    name: web::Path<String>,
+    query: web::Query<ListQuery>,
+) -> impl Responder {
+    web::Json(ratings.0.lock().unwrap().history(&name, query.limit()))
+}
+
+// 部屋番号、ホストからか、中身
+fn parse_relay_header(buf: &[u8]) -> Option<(u32, bool, &[u8])> {
+    if buf.len() < RELAY_HEADER_SIZE {
+        return None;
+    }
+    let (header, payload) = buf.split_at(RELAY_HEADER_SIZE);
+    let room_id = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    match header[4] {
+        0 => Some((room_id, true, payload)),
+        1 => Some((room_id, false, payload)),
+        _ => None,
+    }
+}
+
+// ゲームのデータグラム (`net::Packet`) の先頭にあるセッションと通し番号
+fn packet_seq(body: &[u8]) -> Option<(u32, u32)> {
+    let (_version, session, seq): (u8, u32, u32) = bincode::DefaultOptions::new()
+        .allow_trailing_bytes()
+        .deserialize(body)
+        .ok()?;
+    Some((session, seq))
+}
+
+// 中継先として覚えている相手
+struct RelayPeer {
+    addr: SocketAddr,
+    seen: Instant,
+    // 覚えたときの部屋の鍵 (部屋が作り直されたら別の相手として扱う)
+    key: SessionKey,
+    window: SeqWindow,
+}
+
+// 対戦中の部屋の鍵
+fn live_key(rooms: &Rooms, room_id: u32) -> Option<SessionKey> {
+    let rooms = rooms.0.lock().unwrap();
+    let room = rooms.get(&room_id)?;
+    room.playing.as_ref().map(|_| room.key)
+}
+
+// 直接届かない2人の間でゲームのデータグラムを中継する
+// 対戦中の部屋の鍵でMACが合うものだけ受け取り、(部屋番号, ホストからか) ごとに
+// 最後に送ってきたアドレスを覚えて、もう一方に送る
+fn relay(socket: UdpSocket, rooms: web::Data<Rooms>) {
+    let mut peers: HashMap<(u32, bool), RelayPeer> = HashMap::new();
+    let mut buf = [0; MAX_RELAY_SIZE];
+
+    loop {
+        let (size, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("relay: {}", e);
+                continue;
+            }
+        };
+        let Some((room_id, host, payload)) = parse_relay_header(&buf[..size]) else {
+            continue;
+        };
+        let Some(key) = live_key(&rooms, room_id) else {
+            continue;
+        };
+        let Some((session, seq)) = key.verify(host, payload).and_then(packet_seq) else {
+            continue;
+        };
+        let role = if host { "host" } else { "guest" };
+
+        match peers.get_mut(&(room_id, host)) {
+            Some(peer) if peer.key == key => {
+                // 前に届いたものを送り直されても、アドレスは変えない
+                if !peer.window.accept(session, seq) {
+                    continue;
+                }
+                if peer.addr != from {
+                    println!("relay {} {}: {} -> {}", room_id, role, peer.addr, from);
+                    peer.addr = from;
+                }
+                peer.seen = Instant::now();
+            }
+            _ => {
+                println!("relay {} {}: {}", room_id, role, from);
+                let mut window = SeqWindow::default();
+                window.accept(session, seq);
+                peers.insert(
+                    (room_id, host),
+                    RelayPeer {
+                        addr: from,
+                        seen: Instant::now(),
+                        key,
+                        window,
+                    },
+                );
+                peers.retain(|_, peer| peer.seen.elapsed() < RELAY_EXPIRE);
+            }
+        }
+        match peers.get(&(room_id, !host)) {
+            Some(peer) if peer.key == key => {
+                if let Err(e) = socket.send_to(payload, peer.addr) {
+                    eprintln!("relay {}: {}", peer.addr, e);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn routes(config: &mut web::ServiceConfig) {
+    config
+        .service(create)
+        .service(enter)
+        .service(wait)
+        .service(forward_answer)
+        .service(leave)
+        .service(list)
+        .service(matches)
+        .service(report)
+        .service(leaderboard)
+        .service(player)
+        .service(history);
+}
+
+// HTTPと中継のソケットを同じアドレスで開ける
+fn bind(address: &str) -> io::Result<(TcpListener, UdpSocket)> {
+    let addr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no address"))?;
+
+    let listener = bind_socket(addr, Type::STREAM)?;
+    listener.listen(1024)?;
+    let socket = bind_socket(addr, Type::DGRAM)?;
+
+    Ok((listener.into(), socket.into()))
+}
+
+#[actix_web::main]
+async fn main() -> io::Result<()> {
+    let (listener, socket) = match std::env::args().nth(1) {
+        Some(address) => bind(&address)?,
+        None => bind(DEFAULT_ADDRESS).or_else(|e| {
+            eprintln!("{}: {}", DEFAULT_ADDRESS, e);
+            bind(FALLBACK_ADDRESS)
+        })?,
+    };
+    let ladder = std::env::args()
+        .nth(2)
+        .unwrap_or_else(|| DEFAULT_LADDER.to_string());
+    let rooms = web::Data::new(Rooms::default());
+    let ratings = web::Data::new(Ratings(Mutex::new(Ladder::load(&ladder)?)));
+
+    println!("room server: {}", listener.local_addr()?);
+    println!("ladder: {}", ladder);
+
+    let relay_rooms = rooms.clone();
+    thread::spawn(move || relay(socket, relay_rooms));
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(rooms.clone())
+            .app_data(ratings.clone())
+            .configure(routes)
+    })
+    .listen(listener)?
+    .run()
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use server::{room_answer, room_create, room_enter, room_list, room_wait};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    const VERSION: u8 = 1;
+
+    // 空いているポートで部屋サーバーを立てて、URLを返す
+    fn spawn_server(name: &str) -> String {
+        let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        // 対戦を記録しないのでファイルは作られない
+        let path =
+            std::env::temp_dir().join(format!("invader-room-{}-{}.json", std::process::id(), name));
+        let rooms = web::Data::new(Rooms::default());
+        let ratings = web::Data::new(Ratings(Mutex::new(Ladder::load(path).unwrap())));
+
+        thread::spawn(move || {
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(rooms.clone())
+                    .app_data(ratings.clone())
+                    .configure(routes)
+            })
+            .workers(1)
+            .listen(listener)
+            .unwrap()
+            .run();
+            actix_web::rt::System::new().block_on(server)
+        });
+        url
+    }
+
+    // 届かないアドレス (TEST-NET-1) を知らせたホストの部屋を作る
+    fn create_room(server: &str, room_id: u32) -> RoomRequest {
+        let host = User::new("host", IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        let mut room = RoomRequest::new(room_id, host, VERSION);
+        let Ok(ResultResponse::Ok { token, .. }) = room_create(server, room.clone()) else {
+            panic!("could not create room");
+        };
+        room.token = token;
+        room
+    }
+
+    // 部屋サーバーに聞いて、入ってきた相手に `tick_rate` で返事をするホスト
+    fn answer_guest(
+        server: &str,
+        room: RoomRequest,
+        tick_rate: u32,
+    ) -> thread::JoinHandle<(Hello, ResultResponse)> {
+        let server = server.to_string();
+        thread::spawn(move || {
+            let hello = loop {
+                match room_wait(&server, room.clone()).unwrap() {
+                    WaitResponse::Guest(hello) => break hello,
+                    WaitResponse::Empty => continue,
+                    WaitResponse::Err(e) => panic!("{}", e),
+                }
+            };
+            let reply = Hello {
+                version: hello.version,
+                user: room.user.clone(),
+                key: None,
+                tick_rate: Some(tick_rate),
+            };
+            let answer = Answer {
+                room_id: room.room_id,
+                token: room.token.unwrap(),
+                reply: Handshake::Hello(reply),
+            };
+            (hello, room_answer(&server, answer).unwrap())
+        })
+    }
+
+    #[test]
+    fn enter_reaches_unreachable_host() {
+        let server = spawn_server("enter");
+        let room = create_room(&server, 1);
+        let host = answer_guest(&server, room, 20);
+
+        // 部屋サーバーからホストにはつながらないが、ホストが聞きに来るので入れる
+        let guest = RoomRequest::new(1, User::new("guest", LOCALHOST), VERSION);
+        let entered = room_enter(&server, guest).unwrap();
+        let (hello, answered) = host.join().unwrap();
+
+        assert_eq!(hello.user.name, "guest");
+        assert!(hello.key.is_some());
+        assert!(matches!(answered, ResultResponse::Ok { .. }));
+        let ResultResponse::Ok {
+            user: Some(host),
+            rules,
+            key,
+            ..
+        } = entered
+        else {
+            panic!("could not enter: {:?}", entered);
+        };
+        assert_eq!(host.name, "host");
+        assert_eq!(rules.tick_rate, 20);
+        assert_eq!(key, hello.key);
+        assert!(room_list(&server).unwrap().is_empty());
+    }
+
+    #[test]
+    fn answer_is_checked_for_the_guest() {
+        let server = spawn_server("answer");
+        let room = create_room(&server, 2);
+        let host = answer_guest(&server, room, 1000);
+
+        // 入る側が受け取れないティックレートは部屋サーバーが断る
+        let guest = RoomRequest::new(2, User::new("guest", LOCALHOST), VERSION);
+        let entered = room_enter(&server, guest).unwrap();
+        let (_, answered) = host.join().unwrap();
+
+        assert!(matches!(answered, ResultResponse::Err(_)));
+        assert!(
+            matches!(entered, ResultResponse::Err(reason) if reason.starts_with("host rejected"))
+        );
+        // ホストは次の相手を待てるので一覧に戻る
+        assert_eq!(room_list(&server).unwrap().len(), 1);
+    }
+}
@@ -5,7 +5,8 @@ use bevy::prelude::*;
 use bevy::sprite::collide_aabb::collide;
 use bevy::time::common_conditions::on_timer;
 
-use rand::{seq::SliceRandom, thread_rng, Rng};
+use rand::rngs::StdRng;
+use rand::{seq::SliceRandom, thread_rng, Rng, SeedableRng};
 
 use super::AttackMethod;
 use super::PlayerMethod;
@@ -24,14 +25,23 @@ where
 {
     fn build(&self, app: &mut App) {
         let state = self.setting.in_state;
-        app.add_systems(OnExit(state), entity_despawn::<P, A>)
+        app.init_resource::<EnemyWave>()
+            .add_event::<WaveCreated>()
+            .add_event::<EnemyHit>()
+            .add_event::<EnemyFired>()
+            .add_systems(OnEnter(state), wave_reset)
+            .add_systems(OnExit(state), entity_despawn::<P, A>)
             .add_systems(
                 Update,
                 (
                     move_enemy,
                     move_enemy_attack,
-                    enemy_attack.run_if(on_timer(self.setting.enemy_attack_timer)),
-                    create_enemy.run_if(on_timer(self.setting.enemy_create_timer)),
+                    enemy_attack
+                        .run_if(on_timer(self.setting.enemy_attack_timer))
+                        .run_if(is_enemy_host),
+                    create_enemy
+                        .run_if(on_timer(self.setting.enemy_create_timer))
+                        .run_if(is_enemy_host),
                     enemy_collision::<P, A>,
                 )
                     .run_if(in_state(state)),
@@ -55,8 +65,32 @@ pub enum EnemyType {
 pub struct Enemy {
     pub hp: isize,
     pub enemy_type: EnemyType,
+    // オンラインで同じ敵を指すための番号
+    pub id: u32,
 }
 
+// 次に作る敵の番号
+#[derive(Resource, Default)]
+pub struct EnemyWave(pub u32);
+
+/// 敵を作った (同じ `seed` から同じ敵が作れる)
+#[derive(Event, Clone, Copy)]
+pub struct WaveCreated {
+    pub wave: u32,
+    pub seed: u64,
+}
+
+/// プレイヤーの攻撃が敵に当たった
+#[derive(Event, Clone, Copy)]
+pub struct EnemyHit {
+    pub id: u32,
+    pub damage: isize,
+}
+
+/// 敵が攻撃した (オンラインのゲストはホストから届いたときに攻撃する)
+#[derive(Event, Clone, Copy, Default)]
+pub struct EnemyFired;
+
 #[derive(Bundle)]
 pub struct EnemyBundle {
     sprite_bundle: SpriteBundle,
@@ -73,7 +107,7 @@ pub struct EnemyAttackBundle {
 
 impl Enemy {
     #[inline]
-    fn damage(&mut self, damage: isize) {
+    pub fn damage(&mut self, damage: isize) {
         self.hp -= damage
     }
 }
@@ -103,7 +137,7 @@ impl EnemyType {
         }
     }
     #[inline]
-    fn translation(&self) -> Vec2 {
+    fn translation(&self, rng: &mut impl Rng) -> Vec2 {
         let range = match self {
             EnemyType::Normal => (
                 -WINDOW_WIDTH as i32 / 2..WINDOW_WIDTH as i32 / 2,
@@ -115,26 +149,24 @@ impl EnemyType {
             ),
         };
 
-        let mut rng = thread_rng();
-
         let x = rng.gen_range(range.0);
         let y = rng.gen_range(range.1);
 
         Vec2::new(x as f32, y as f32)
     }
     #[inline]
-    pub fn random() -> EnemyType {
-        *Self::ENEMY_TYPES.choose(&mut thread_rng()).unwrap()
+    pub fn random(rng: &mut impl Rng) -> EnemyType {
+        *Self::ENEMY_TYPES.choose(rng).unwrap()
     }
 }
 
 impl EnemyBundle {
     #[inline]
-    pub fn new(enemy_type: EnemyType, texture: Texture) -> EnemyBundle {
+    pub fn new(enemy_type: EnemyType, texture: Texture, translation: Vec2, id: u32) -> EnemyBundle {
         EnemyBundle {
             sprite_bundle: SpriteBundle {
                 transform: Transform {
-                    translation: enemy_type.translation().extend(0.0),
+                    translation: translation.extend(0.0),
                     scale: enemy_type.scale().extend(0.0),
                     ..default()
                 },
@@ -148,6 +180,7 @@ impl EnemyBundle {
             enemy: Enemy {
                 hp: enemy_type.hp(),
                 enemy_type,
+                id,
             },
             collider: EnemyCollider,
         }
@@ -192,6 +225,7 @@ pub fn enemy_collision<T, A>(
     mut enemy_query: Query<EnemyQuery, With<EnemyCollider>>,
     mut attack_query: Query<(Entity, &Transform, &mut A), With<A>>,
     mut update_info_event: EventWriter<UpdateInfo>,
+    mut hit_event: EventWriter<EnemyHit>,
 ) where
     T: Component + PlayerMethod,
     A: Component + AttackMethod,
@@ -211,6 +245,10 @@ pub fn enemy_collision<T, A>(
                 let mut player = player_query.single_mut();
 
                 if let Some(enemy) = enemy.as_mut() {
+                    hit_event.send(EnemyHit {
+                        id: enemy.id,
+                        damage: player_attack.hp(),
+                    });
                     enemy.damage(player_attack.hp());
 
                     if enemy.hp <= 0 {
@@ -271,12 +309,23 @@ fn enemy_attack(
     mut commands: Commands,
     texture: Res<TextureResource>,
     enemy_query: Query<&Transform, With<Enemy>>,
+    mut fired_event: EventWriter<EnemyFired>,
+) {
+    spawn_enemy_attack(&mut commands, texture.enemy_attack.clone(), &enemy_query);
+    fired_event.send_default();
+}
+
+/// 全ての敵から攻撃を出す
+pub fn spawn_enemy_attack(
+    commands: &mut Commands,
+    texture: Texture,
+    enemy_query: &Query<&Transform, With<Enemy>>,
 ) {
-    for transform in &enemy_query {
+    for transform in enemy_query {
         // 敵のタイプによってAttackTypeを変える
         commands.spawn(EnemyAttackBundle::new(
             AttackType::EnemyNormal,
-            texture.enemy_attack.clone(),
+            texture.clone(),
             transform.translation,
         ));
     }
@@ -303,8 +352,42 @@ pub fn move_enemy_attack(
 
 // 敵を作る
 #[inline]
-fn create_enemy(mut commands: Commands, texture: Res<TextureResource>) {
-    for _ in 1..=ENEMY_CREAT_NUMBER {
-        commands.spawn(EnemyBundle::new(EnemyType::random(), texture.enemy.clone()));
+fn create_enemy(
+    mut commands: Commands,
+    texture: Res<TextureResource>,
+    mut wave: ResMut<EnemyWave>,
+    mut wave_event: EventWriter<WaveCreated>,
+) {
+    let seed = thread_rng().gen();
+    spawn_wave(&mut commands, texture.enemy.clone(), wave.0, seed);
+
+    wave_event.send(WaveCreated { wave: wave.0, seed });
+    wave.0 += 1;
+}
+
+/// `seed` から敵をまとめて作る
+pub fn spawn_wave(commands: &mut Commands, texture: Texture, wave: u32, seed: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    for i in 0..ENEMY_CREAT_NUMBER as u32 {
+        let enemy_type = EnemyType::random(&mut rng);
+        let translation = enemy_type.translation(&mut rng);
+        let id = wave * ENEMY_CREAT_NUMBER as u32 + i;
+
+        commands.spawn(EnemyBundle::new(
+            enemy_type,
+            texture.clone(),
+            translation,
+            id,
+        ));
     }
 }
+
+fn wave_reset(mut wave: ResMut<EnemyWave>) {
+    wave.0 = 0;
+}
+
+// オンラインのゲストは敵を作らず、攻撃もしない (ホストから届く)
+fn is_enemy_host(role: Option<Res<Role>>) -> bool {
+    role.is_none_or(|role| *role == Role::Host)
+}
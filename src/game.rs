@@ -1,18 +1,23 @@
 mod connect;
 mod game_menu;
 mod game_over;
+mod net;
 mod server;
+mod snapshot;
 
-pub use connect::ConnectPlugin;
+pub use connect::{ConnectPlugin, MatchFinished};
 pub use game_menu::*;
 pub use game_over::*;
+pub use net::*;
 pub use server::*;
+pub use snapshot::*;
 
 use std::marker::PhantomData;
 use std::time::Duration;
 
 use bevy::app::PluginGroupBuilder;
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use self::AttackType::*;
 use crate::entity::{AttackMethod, DamageEventMethod, EnemyPlugin, PlayerMethod, PlayerPlugin};
@@ -52,7 +57,12 @@ pub enum GameMode {
     Single,
     Tow,
     VS,
+    Coop,
     Connect,
+    // CPUの強さを選ぶ
+    Cpu,
+    // 1台のキーボードでVSを始める
+    HotSeat,
     #[default]
     Disabled,
 }
@@ -69,7 +79,7 @@ impl Plugin for GamePlugin {
                 Update,
                 game_menu_system.run_if(in_state(GameMode::Disabled)),
             )
-            .add_plugins((SinglePlay, TwoPlay, VSPlayer));
+            .add_plugins((SinglePlay, TwoPlay, VSPlayer, CoopPlay));
     }
 }
 
@@ -99,6 +109,7 @@ pub struct PluginSetting<P: Clone, A: Clone, E: Clone> {
     pub player: PhantomData<P>,
     pub enemy: PhantomData<A>,
     pub event: PhantomData<E>,
+    #[allow(dead_code)]
     pub player_attack_timer: Duration,
     pub enemy_create_timer: Duration,
     pub enemy_attack_timer: Duration,
@@ -131,7 +142,7 @@ pub struct Attack {
     pub attack: AttackType,
 }
 
-#[derive(Component, Clone, Copy, PartialEq, Debug)]
+#[derive(Component, Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
 pub enum AttackType {
     Normal,
     Power,
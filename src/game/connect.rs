@@ -1,132 +1,644 @@
-use std::{
-    io::Read,
-    net::{IpAddr, TcpListener},
-    str::from_utf8,
-    sync::mpsc::channel,
-    thread,
-    time::Duration,
-};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use bevy::{prelude::*, time::common_conditions::on_timer};
+use bevy::prelude::*;
 use bevy_simple_text_input::{TextInput, TextInputSubmitEvent};
-use local_ip_address::local_ip;
+use socket2::Type;
 
+use crate::setting::Setting;
 use crate::{despawn_screen, FontResource};
 
-use super::{room_create, room_enter, GameMode, ResultResponse, RoomRequest, User};
+mod lan;
+mod rating;
+mod room_list;
+
+use super::{
+    bind_dual_stack, match_report, negotiate_tick_rate, read_handshake, room_answer, room_create,
+    room_enter, room_leave, room_wait, version_mismatch, write_handshake, Answer, Capability,
+    GameMode, Handshake, Hello, MatchRecord, MatchReport, OnlineMode, Relay, ResultResponse, Role,
+    RoomRequest, Rules, Server, SessionKey, User, WaitResponse, MAX_TICK_RATE, MIN_TICK_RATE,
+    PROTOCOL_VERSION,
+};
 
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, States)]
 enum ConnectState {
+    // サーバーの返事を待っている
+    Request,
+    // 相手が部屋に入るのを待っている
     Wait,
     #[default]
     Disabled,
 }
 
+// 相手を待つ最大時間
+const WAIT_TIMEOUT: Duration = Duration::from_secs(180);
+// ハンドシェイクで相手を待つときのタイムアウト
+const READ_TIMEOUT: Duration = Duration::from_secs(3);
+// 接続がないか確認する間隔
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+
 pub struct ConnectPlugin;
 
 impl Plugin for ConnectPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(RoomRequest::new(0, User::new("", local_ip().unwrap(), 0.0)))
+        // アドレスは接続画面に入ったときに設定から決める
+        let user = User::new("", IpAddr::V4(Ipv4Addr::LOCALHOST));
+        app.insert_resource(RoomRequest::new(0, user, PROTOCOL_VERSION))
             .add_state::<ConnectState>()
-            .add_systems(OnEnter(GameMode::Connect), connect_setup)
+            .add_event::<MatchFinished>()
+            .add_systems(
+                OnEnter(GameMode::Connect),
+                // 開けられなかったときの `BindError` を画面に出す
+                (connect_bind, apply_deferred, connect_setup).chain(),
+            )
             .add_systems(OnExit(GameMode::Connect), despawn_screen::<ConnectScreen>)
-            .add_systems(OnEnter(ConnectState::Wait), wait)
+            .add_systems(
+                OnExit(GameMode::VS),
+                room_close.run_if(resource_exists::<Server>()),
+            )
+            .add_systems(
+                OnExit(GameMode::Coop),
+                room_close.run_if(resource_exists::<Server>()),
+            )
+            .add_systems(
+                Update,
+                match_report_send.run_if(on_event::<MatchFinished>()),
+            )
+            .add_systems(OnEnter(ConnectState::Wait), (wait, wait_setup))
+            .add_systems(OnExit(ConnectState::Wait), despawn_screen::<WaitScreen>)
             .add_systems(
                 Update,
                 (
-                    connect_button_system,
+                    // ソケットが開けなければ対戦を始められない
+                    connect_button_system.run_if(resource_exists::<Server>()),
+                    mode_button_system,
+                    rules_button_system,
                     input_event,
                     focus,
-                    measure_delta_seconds.run_if(on_timer(Duration::from_micros(150))),
                 )
                     .run_if(in_state(ConnectState::Disabled)),
+            )
+            .add_systems(
+                Update,
+                room_task_system.run_if(in_state(ConnectState::Request)),
+            )
+            .add_plugins((
+                room_list::RoomListPlugin,
+                rating::RatingPlugin,
+                lan::LanPlugin,
+            ))
+            .add_systems(
+                Update,
+                (
+                    wait_system,
+                    wait_button_system,
+                    elapsed_update,
+                    failed_update,
+                )
+                    .run_if(in_state(ConnectState::Wait)),
             );
     }
 }
 
-fn parse_user(str: &str) -> User {
-    #[derive(serde::Deserialize)]
-    struct ParseUser {
-        name: String,
-        ip: [u8; 4],
-        delta_seconds: f32,
+// 部屋サーバーや相手とのやり取り
+// 止まって待つ通信なので、タスクプールではなく専用のスレッドで動かして結果をチャンネルで受け取る
+struct Worker<T>(Mutex<Receiver<Result<T, String>>>);
+
+impl<T: Send + 'static> Worker<T> {
+    fn spawn(f: impl FnOnce() -> Result<T, String> + Send + 'static) -> Worker<T> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            // 待っている側がもういなければ結果は捨てる
+            let _ = sender.send(f());
+        });
+        Worker(Mutex::new(receiver))
     }
-    let ParseUser {
-        name,
-        ip,
-        delta_seconds,
-    } = serde_json::from_str(str).unwrap();
-
-    User {
-        name,
-        ip: IpAddr::from(ip),
-        delta_seconds,
+
+    // 終わっていれば結果を返す
+    fn poll(&self) -> Option<Result<T, String>> {
+        match self.0.lock().unwrap().try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err("request failed".to_string())),
+        }
     }
 }
 
-const RECV_TIMEOUT: Duration = Duration::from_millis(500);
+// 部屋サーバーの部屋なら部屋を作ったときの鍵と同じもの、LANなら相手が作った鍵を使う
+fn session_key(
+    created: Option<SessionKey>,
+    received: Option<SessionKey>,
+) -> Result<SessionKey, String> {
+    match (created, received) {
+        (Some(created), Some(received)) if created == received => Ok(created),
+        (None, Some(received)) => Ok(received),
+        _ => Err("opponent sent an invalid session key".to_string()),
+    }
+}
+
+// ゲームで使うソケットを開けられなかった理由 (接続画面に出す)
+#[derive(Resource)]
+struct BindError(String);
+
+// ゲームで使うソケットを先に開けて、ポートを相手に知らせられるようにする
+fn connect_bind(mut commands: Commands, setting: Res<Setting>, mut room: ResMut<RoomRequest>) {
+    let ip = setting.local_ip();
+    room.user.ip = ip;
+    room.token = None;
+    room.user.max_tick_rate = setting.max_tick_rate.clamp(MIN_TICK_RATE, MAX_TICK_RATE);
+    commands.remove_resource::<SessionKey>();
+
+    match Server::bind(ip).and_then(|server| Ok((server.port()?, server))) {
+        Ok((port, server)) => {
+            room.user.net_port = port;
+            commands.insert_resource(server);
+            commands.remove_resource::<BindError>();
+        }
+        Err(e) => {
+            eprintln!("bind {}: {}", ip, e);
+            // 前のソケットが残っていても使わない
+            commands.remove_resource::<Server>();
+            commands.insert_resource(BindError(format!(
+                "could not open a game socket on {}: {}",
+                ip, e
+            )));
+        }
+    }
+}
+
+// 相手の情報を待つソケット (ポートは部屋を作る前に決める)
+#[derive(Resource)]
+struct HostListener(TcpListener);
+
+// 空いているポートで待ち受けて、そのポートを部屋の情報に入れる
+// IPv4とIPv6のどちらから来ても受け付ける
+fn host_listen(commands: &mut Commands, room: &mut RoomRequest) -> io::Result<()> {
+    let socket = bind_dual_stack(room.user.ip, Type::STREAM)?;
+    socket.listen(8)?;
+    let listener: TcpListener = socket.into();
+    room.user.port = listener.local_addr()?.port();
+    commands.insert_resource(HostListener(listener));
+    Ok(())
+}
+
+// サーバーへのリクエスト
+#[derive(Resource)]
+struct RoomTask {
+    section: ConnectSection,
+    task: Worker<ResultResponse>,
+    // 部屋サーバーで見つけた相手なら部屋番号 (中継に使う)
+    relay: Option<u32>,
+}
+
+// 相手をどこで募集しているか
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+enum Hosting {
+    // 部屋サーバーに部屋を作った
+    Server,
+    // LANに知らせている
+    Lan,
+}
+
+// 相手を待っている間の状態
+#[derive(Resource)]
+struct WaitTask {
+    // 相手と鍵と決めたティックレート
+    task: Worker<(User, SessionKey, u32)>,
+    // ハンドシェイクに失敗した相手と理由 (待ち続けながら画面に出す)
+    failed: Mutex<Receiver<String>>,
+    cancel: Arc<AtomicBool>,
+    start: Instant,
+    hosting: Hosting,
+}
 
 // 相手が部屋に入るまで待つ
+// 部屋サーバーの部屋なら部屋サーバーに聞き、LANなら相手がつないでくるのを待つ
 fn wait(
     mut commands: Commands,
+    listener: Option<Res<HostListener>>,
+    hosting: Res<Hosting>,
+    key: Option<Res<SessionKey>>,
+    room: Res<RoomRequest>,
+    setting: Res<Setting>,
+) {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let listener = listener
+        .ok_or_else(|| "not listening".to_string())
+        .and_then(|listener| listener.0.try_clone().map_err(|e| e.to_string()));
+    commands.remove_resource::<HostListener>();
+
+    // 部屋サーバーが部屋を作ったときにくれた鍵
+    let key = key.filter(|_| *hosting == Hosting::Server).map(|key| *key);
+    let flag = cancel.clone();
+    let (sender, failed) = mpsc::channel();
+    let task = match *hosting {
+        Hosting::Server => {
+            let server = setting.server.clone();
+            let room = room.clone();
+            Worker::spawn(move || wait_guest(&server, &room, &flag, key, &sender))
+        }
+        Hosting::Lan => {
+            let host = room.user.clone();
+            let rules = room.rules.clone();
+            Worker::spawn(move || wait_opponent(listener?, &flag, &host, &rules, key, &sender))
+        }
+    };
+
+    commands.insert_resource(WaitTask {
+        task,
+        failed: Mutex::new(failed),
+        cancel,
+        start: Instant::now(),
+        hosting: *hosting,
+    });
+}
+
+// 相手がつないでくるのを待つ (ハンドシェイクに失敗したら次を待つ)
+fn wait_opponent(
+    server: TcpListener,
+    cancel: &AtomicBool,
+    host: &User,
+    rules: &Rules,
+    key: Option<SessionKey>,
+    failed: &Sender<String>,
+) -> Result<(User, SessionKey, u32), String> {
+    server.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+    let start = Instant::now();
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return Err("cancelled".to_string());
+        }
+        if start.elapsed() > WAIT_TIMEOUT {
+            return Err("timed out waiting for opponent".to_string());
+        }
+        match server.accept() {
+            Ok((socket, addr)) => match handshake(socket, host, rules, key) {
+                Ok(opponent) => return Ok(opponent),
+                Err(e) => {
+                    eprintln!("handshake {}: {}", addr, e);
+                    let _ = failed.send(format!("{} could not join: {}", addr, e));
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+// 部屋サーバーに入ってきた相手を聞き、返事をする (断ったら次を待つ)
+// ホストからつなぐので、ホストがNATの内側にいても入ってこられる
+fn wait_guest(
+    server: &str,
+    room: &RoomRequest,
+    cancel: &AtomicBool,
+    key: Option<SessionKey>,
+    failed: &Sender<String>,
+) -> Result<(User, SessionKey, u32), String> {
+    let token = room.token.ok_or_else(|| "no host token".to_string())?;
+
+    let start = Instant::now();
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return Err("cancelled".to_string());
+        }
+        if start.elapsed() > WAIT_TIMEOUT {
+            return Err("timed out waiting for opponent".to_string());
+        }
+        let hello = match room_wait(server, room.clone()).map_err(request_error)? {
+            WaitResponse::Guest(hello) => hello,
+            WaitResponse::Empty => continue,
+            WaitResponse::Err(e) => return Err(e),
+        };
+
+        // 聞いている間に待つのをやめていたら断る
+        let checked = if cancel.load(Ordering::Relaxed) {
+            Err("host cancelled".to_string())
+        } else {
+            check_hello(&hello, &room.user, &room.rules, key)
+        };
+        let reply = match &checked {
+            Ok((reply, _, _)) => Handshake::Hello(reply.clone()),
+            Err(reason) => Handshake::Reject(reason.clone()),
+        };
+        let answer = Answer {
+            room_id: room.room_id,
+            token,
+            reply,
+        };
+        let answered = match room_answer(server, answer).map_err(request_error)? {
+            ResultResponse::Ok { .. } => checked,
+            ResultResponse::Err(e) => Err(e),
+        };
+
+        match answered {
+            Ok((_, key, tick_rate)) => return Ok((hello.user, key, tick_rate)),
+            Err(e) => {
+                eprintln!("answer {}: {}", hello.user.name, e);
+                let _ = failed.send(format!("{} could not join: {}", hello.user.name, e));
+            }
+        }
+    }
+}
+
+// 相手の `Hello` を確かめて、返す `Hello` と鍵を決める
+// ティックレートはここで決めて返事に入れる
+fn check_hello(
+    hello: &Hello,
+    host: &User,
+    rules: &Rules,
+    key: Option<SessionKey>,
+) -> Result<(Hello, SessionKey, u32), String> {
+    if hello.version != PROTOCOL_VERSION {
+        return Err(version_mismatch(PROTOCOL_VERSION, hello.version));
+    }
+    let tick_rate = negotiate_tick_rate(rules, host, &hello.user)?;
+    let key = session_key(key, hello.key)?;
+    let reply = Hello {
+        version: PROTOCOL_VERSION,
+        user: host.clone(),
+        key: None,
+        tick_rate: Some(tick_rate),
+    };
+    Ok((reply, key, tick_rate))
+}
+
+// 相手の `Hello` を確かめて自分の `Hello` を返し、`Ack` を待つ
+fn handshake(
+    mut socket: TcpStream,
+    host: &User,
+    rules: &Rules,
+    key: Option<SessionKey>,
+) -> io::Result<(User, SessionKey, u32)> {
+    socket.set_nonblocking(false)?;
+    socket.set_read_timeout(Some(READ_TIMEOUT))?;
+    socket.set_write_timeout(Some(READ_TIMEOUT))?;
+
+    let Handshake::Hello(hello) = read_handshake(&mut socket)? else {
+        return Err(io::Error::new(ErrorKind::InvalidData, "expected hello"));
+    };
+    let (reply, key, tick_rate) = match check_hello(&hello, host, rules, key) {
+        Ok(checked) => checked,
+        Err(reason) => {
+            write_handshake(&mut socket, &Handshake::Reject(reason.clone()))?;
+            return Err(io::Error::other(reason));
+        }
+    };
+
+    write_handshake(&mut socket, &Handshake::Hello(reply))?;
+
+    match read_handshake(&mut socket)? {
+        Handshake::Ack => Ok((hello.user, key, tick_rate)),
+        Handshake::Reject(reason) => Err(io::Error::other(reason)),
+        Handshake::Hello(_) => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "unexpected handshake",
+        )),
+    }
+}
+
+fn wait_setup(
+    mut commands: Commands,
+    font: Res<FontResource>,
     room: Res<RoomRequest>,
+    hosting: Res<Hosting>,
+) {
+    let info = match *hosting {
+        Hosting::Server => format!("room id: {}", room.room_id),
+        Hosting::Lan => format!("LAN: {}", room.user.ip),
+    };
+    let text_style = |font_size| TextStyle {
+        font: font.0.clone(),
+        font_size,
+        color: Color::WHITE,
+    };
+
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    position_type: PositionType::Absolute,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    flex_direction: FlexDirection::Column,
+                    ..default()
+                },
+                background_color: Color::BLACK.into(),
+                z_index: ZIndex::Global(1),
+                ..default()
+            },
+            WaitScreen,
+        ))
+        .with_children(|parent| {
+            parent.spawn(TextBundle::from_section(
+                "Waiting for opponent…",
+                text_style(50.),
+            ));
+            parent.spawn(TextBundle::from_section(info, text_style(40.)));
+            parent.spawn((TextBundle::from_section("0s", text_style(40.)), ElapsedText));
+            parent.spawn((
+                TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        color: Color::ORANGE_RED,
+                        ..text_style(30.)
+                    },
+                ),
+                FailedText,
+            ));
+            parent
+                .spawn((
+                    ButtonBundle {
+                        style: Style {
+                            width: Val::Px(200.),
+                            height: Val::Px(60.),
+                            margin: UiRect::vertical(Val::Px(20.)),
+                            justify_content: JustifyContent::Center,
+                            align_items: AlignItems::Center,
+                            ..default()
+                        },
+                        ..default()
+                    },
+                    WaitButton::Cancel,
+                ))
+                .with_children(|parent| {
+                    parent.spawn(TextBundle::from_section(
+                        "Cancel",
+                        TextStyle {
+                            color: Color::BLACK,
+                            ..text_style(40.)
+                        },
+                    ));
+                });
+        });
+}
+
+// 待ち時間を表示する
+fn elapsed_update(wait: Res<WaitTask>, mut elapsed_query: Query<&mut Text, With<ElapsedText>>) {
+    for mut text in &mut elapsed_query {
+        text.sections[0].value = format!("{}s", wait.start.elapsed().as_secs());
+    }
+}
+
+// 入ろうとして失敗した相手を表示する (最後のものだけ)
+fn failed_update(wait: Res<WaitTask>, mut failed_query: Query<&mut Text, With<FailedText>>) {
+    let Some(message) = wait.failed.lock().unwrap().try_iter().last() else {
+        return;
+    };
+    for mut text in &mut failed_query {
+        text.sections[0].value = message.clone();
+    }
+}
+
+// 相手が来たか確認する
+fn wait_system(
+    mut commands: Commands,
+    wait: Res<WaitTask>,
+    mut status_query: Query<&mut Text, With<StatusText>>,
+    room: Res<RoomRequest>,
+    setting: Res<Setting>,
     mut connect_state: ResMut<NextState<ConnectState>>,
     mut game_state: ResMut<NextState<GameMode>>,
 ) {
-    // 相手の情報を受け取るサーバー
-    let ip = room.user.ip.to_string();
+    let Some(result) = wait.task.poll() else {
+        return;
+    };
+    commands.remove_resource::<WaitTask>();
+    connect_state.set(ConnectState::Disabled);
+
+    match result {
+        Ok((user, key, tick_rate)) => {
+            let relay = (wait.hosting == Hosting::Server).then_some(room.room_id);
+            let relay = relay.filter(|_| user.capabilities.contains(&Capability::Relay));
+            set_relay(&mut commands, &setting, relay);
+            commands.insert_resource(user);
+            commands.insert_resource(key);
+            commands.insert_resource(Rules {
+                tick_rate,
+                ..room.rules.clone()
+            });
+            commands.insert_resource(Role::Host);
+            game_state.set(game_mode(room.mode));
+        }
+        Err(e) => {
+            set_status(&mut status_query, e);
+            if wait.hosting == Hosting::Server {
+                leave(&setting.server, room.clone());
+            }
+        }
+    }
+}
 
-    let (user_s, user_r) = channel();
-    let (state_s, state_r) = channel();
-    let (game_state_s, game_state_r) = channel();
+fn wait_button_system(
+    mut commands: Commands,
+    interaction: Query<(&Interaction, &WaitButton), Changed<Interaction>>,
+    wait: Res<WaitTask>,
+    mut status_query: Query<&mut Text, With<StatusText>>,
+    room: Res<RoomRequest>,
+    setting: Res<Setting>,
+    mut connect_state: ResMut<NextState<ConnectState>>,
+) {
+    for (interaction, button) in &interaction {
+        if *interaction == Interaction::Pressed {
+            match *button {
+                WaitButton::Cancel => {
+                    // 待ち受けを止める
+                    wait.cancel.store(true, Ordering::Relaxed);
+                    commands.remove_resource::<WaitTask>();
 
-    thread::spawn(move || {
-        let server = TcpListener::bind((ip, 8888)).expect("サーバーエラー");
-        match server.accept() {
-            Ok((mut socket, addr)) => {
-                println!("to addr {:?}", addr);
-
-                let mut buf = [0; 120];
-                match socket.read(&mut buf) {
-                    Ok(bytes) => {
-                        println!("{}", bytes);
-                        let str = from_utf8(&buf[..bytes]).unwrap();
-                        println!("{}", str);
-                        let user = parse_user(str);
-                        println!("{:?}", user);
-
-                        user_s.send(user).expect("user send err");
-                        state_s.send(ConnectState::Disabled).unwrap();
-                        game_state_s.send(GameMode::VS).unwrap();
+                    set_status(&mut status_query, "cancelled");
+                    if wait.hosting == Hosting::Server {
+                        leave(&setting.server, room.clone());
                     }
-                    Err(e) => eprintln!("{}", e),
+                    connect_state.set(ConnectState::Disabled);
                 }
             }
-            Err(e) => eprintln!("{}", e),
         }
-    })
-    .join()
-    .unwrap();
-
-    if let Ok(user) = user_r.recv_timeout(RECV_TIMEOUT) {
-        commands.insert_resource(user);
     }
-    if let Ok(s) = state_r.recv_timeout(RECV_TIMEOUT) {
-        connect_state.set(s);
+}
+
+// 部屋を閉じる (結果は待たない)
+fn leave(server: &str, room: RoomRequest) {
+    let server = server.to_string();
+    thread::spawn(move || {
+        if let Err(e) = room_leave(&server, room) {
+            eprintln!("{}", e);
+        }
+    });
+}
+
+// 対戦が終わったらホストが部屋を閉じる (観戦できる対戦の一覧から消す)
+fn room_close(
+    setting: Res<Setting>,
+    room: Res<RoomRequest>,
+    hosting: Option<Res<Hosting>>,
+    role: Option<Res<Role>>,
+) {
+    if role.as_deref() == Some(&Role::Host) && hosting.as_deref() == Some(&Hosting::Server) {
+        leave(&setting.server, room.clone());
     }
-    if let Ok(s) = game_state_r.recv_timeout(RECV_TIMEOUT) {
-        game_state.set(s);
+}
+
+/// VSの対戦が終わった (ホストが部屋サーバーに記録してもらう)
+#[derive(Event)]
+pub struct MatchFinished {
+    /// 最後の試合の番号
+    pub round: u32,
+    pub record: MatchRecord,
+}
+
+// 部屋サーバーで見つけた相手との対戦なら結果を送る (結果は待たない)
+fn match_report_send(
+    mut event: EventReader<MatchFinished>,
+    setting: Res<Setting>,
+    room: Res<RoomRequest>,
+    hosting: Option<Res<Hosting>>,
+) {
+    for MatchFinished { round, record } in event.read() {
+        let (Some(&Hosting::Server), Some(token)) = (hosting.as_deref(), room.token) else {
+            continue;
+        };
+        let server = setting.server.clone();
+        let report = MatchReport {
+            room_id: room.room_id,
+            token,
+            round: *round,
+            record: record.clone(),
+        };
+        thread::spawn(move || {
+            if let Err(e) = match_report(&server, report) {
+                eprintln!("{}", e);
+            }
+        });
     }
+}
 
-    println!("end server...");
+fn set_status(status_query: &mut Query<&mut Text, With<StatusText>>, message: impl Into<String>) {
+    let message = message.into();
+    for mut text in status_query.iter_mut() {
+        text.sections[0].value = message.clone();
+    }
 }
 
-fn connect_setup(mut commands: Commands, font: Res<FontResource>) {
+fn connect_setup(
+    mut commands: Commands,
+    font: Res<FontResource>,
+    setting: Res<Setting>,
+    room: Res<RoomRequest>,
+    bind_error: Option<Res<BindError>>,
+) {
     let button_bundle = ButtonBundle {
         style: Style {
             width: Val::Px(200.),
             height: Val::Px(60.),
-            margin: UiRect::vertical(Val::Px(20.)),
+            margin: UiRect::all(Val::Px(10.)),
             justify_content: JustifyContent::Center,
             align_items: AlignItems::Center,
             ..default()
@@ -140,7 +652,12 @@ fn connect_setup(mut commands: Commands, font: Res<FontResource>) {
     };
     let text_style = TextStyle {
         font: font.0.clone(),
-        font_size: 60.,
+        font_size: 50.,
+        color: Color::WHITE,
+    };
+    let server_text_style = TextStyle {
+        font: font.0.clone(),
+        font_size: 30.,
         color: Color::WHITE,
     };
     commands
@@ -159,6 +676,35 @@ fn connect_setup(mut commands: Commands, font: Res<FontResource>) {
             ConnectScreen,
         ))
         .with_children(|parent| {
+            parent.spawn((
+                TextBundle::from_sections([
+                    TextSection::new("server: ", server_text_style.clone()),
+                    TextSection::new(setting.server.clone(), server_text_style.clone()),
+                ]),
+                ServerText,
+            ));
+            parent.spawn((
+                NodeBundle {
+                    style: Style {
+                        width: Val::Px(400.0),
+                        border: UiRect::all(Val::Px(5.0)),
+                        padding: UiRect::all(Val::Px(5.0)),
+                        margin: UiRect::vertical(Val::Px(5.)),
+                        ..default()
+                    },
+                    border_color: BorderColor(Color::BLACK),
+                    background_color: Color::WHITE.into(),
+                    ..default()
+                },
+                TextInput {
+                    text_style: TextStyle {
+                        color: Color::BLACK,
+                        ..server_text_style.clone()
+                    },
+                    inactive: true,
+                },
+                InfoSection::Server,
+            ));
             parent.spawn(TextBundle::from_section("name", text_style.clone()));
             parent.spawn((
                 NodeBundle {
@@ -183,6 +729,7 @@ fn connect_setup(mut commands: Commands, font: Res<FontResource>) {
                 },
                 InfoSection::Name,
             ));
+            rating::spawn_rating(parent, server_text_style.clone());
             parent.spawn(TextBundle::from_section("room id", text_style.clone()));
             parent.spawn((
                 NodeBundle {
@@ -207,19 +754,75 @@ fn connect_setup(mut commands: Commands, font: Res<FontResource>) {
                 },
                 InfoSection::RoomId,
             ));
-            parent
-                .spawn((button_bundle.clone(), ConnectSection::Create))
-                .with_children(|parent| {
-                    parent.spawn(TextBundle::from_section(
-                        "Create",
-                        button_text_style.clone(),
-                    ));
-                });
-            parent
-                .spawn((button_bundle, ConnectSection::Enter))
-                .with_children(|parent| {
-                    parent.spawn(TextBundle::from_section("Enter", button_text_style));
-                });
+            parent.spawn(NodeBundle::default()).with_children(|parent| {
+                parent
+                    .spawn((
+                        ButtonBundle {
+                            style: Style {
+                                width: Val::Px(280.),
+                                ..button_bundle.style.clone()
+                            },
+                            ..button_bundle.clone()
+                        },
+                        ModeButton,
+                    ))
+                    .with_children(|parent| {
+                        parent.spawn((
+                            TextBundle::from_section(
+                                mode_text(room.mode),
+                                button_text_style.clone(),
+                            ),
+                            ModeText,
+                        ));
+                    });
+                parent
+                    .spawn((
+                        ButtonBundle {
+                            style: Style {
+                                width: Val::Px(320.),
+                                ..button_bundle.style.clone()
+                            },
+                            ..button_bundle.clone()
+                        },
+                        RulesButton,
+                    ))
+                    .with_children(|parent| {
+                        parent.spawn((
+                            TextBundle::from_section(
+                                rules_text(&setting, &room.rules),
+                                button_text_style.clone(),
+                            ),
+                            RulesText,
+                        ));
+                    });
+            });
+            parent.spawn(NodeBundle::default()).with_children(|parent| {
+                parent
+                    .spawn((button_bundle.clone(), ConnectSection::Create))
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle::from_section(
+                            "Create",
+                            button_text_style.clone(),
+                        ));
+                    });
+                parent
+                    .spawn((button_bundle.clone(), ConnectSection::Enter))
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle::from_section("Enter", button_text_style.clone()));
+                    });
+                parent
+                    .spawn((button_bundle, lan::HostLanButton))
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle::from_section("Host LAN", button_text_style));
+                    });
+            });
+            let status = bind_error.map_or(String::new(), |e| e.0.clone());
+            parent.spawn((
+                TextBundle::from_section(status, server_text_style.clone()),
+                StatusText,
+            ));
+            room_list::spawn_room_list(parent);
+            lan::spawn_lan_list(parent);
         });
 }
 
@@ -236,28 +839,55 @@ fn focus(
     }
 }
 
-// 何秒毎に送ればいいか
-fn measure_delta_seconds(
-    mut delta_seconds: Local<(f32, u32)>,
-    time: Res<Time>,
-    mut room: ResMut<RoomRequest>,
-) {
-    delta_seconds.1 += 1;
-    delta_seconds.0 += time.delta_seconds();
-    let delta_time = delta_seconds.0 / delta_seconds.1 as f32;
-    room.user.delta_seconds = delta_time;
-}
-
 #[derive(Component)]
 struct ConnectScreen;
 
+// 接続先のサーバーを表示する
+#[derive(Component)]
+struct ServerText;
+
 #[derive(Component)]
 enum InfoSection {
+    Server,
     Name,
     RoomId,
 }
 
+// 接続の状態やエラーを表示する
+#[derive(Component)]
+struct StatusText;
+
+#[derive(Component)]
+struct WaitScreen;
+
+// 待ち時間
+#[derive(Component)]
+struct ElapsedText;
+
+// ハンドシェイクに失敗した相手
+#[derive(Component)]
+struct FailedText;
+
+#[derive(Component)]
+enum WaitButton {
+    Cancel,
+}
+
+// 部屋を作るときのゲームを切り替える
+#[derive(Component)]
+struct ModeButton;
+
+#[derive(Component)]
+struct ModeText;
+
+// VSのルールを切り替える
 #[derive(Component)]
+struct RulesButton;
+
+#[derive(Component)]
+struct RulesText;
+
+#[derive(Component, Clone, Copy)]
 enum ConnectSection {
     Create,
     Enter,
@@ -266,56 +896,206 @@ enum ConnectSection {
 fn connect_button_system(
     mut commands: Commands,
     interaction: Query<(&Interaction, &ConnectSection), Changed<Interaction>>,
+    mut status_query: Query<&mut Text, With<StatusText>>,
     room: Res<RoomRequest>,
+    setting: Res<Setting>,
     mut connect_state: ResMut<NextState<ConnectState>>,
-    mut game_state: ResMut<NextState<GameMode>>,
 ) {
     for (interaction, section) in &interaction {
         if *interaction == Interaction::Pressed {
-            let room = room.clone();
-            match *section {
-                ConnectSection::Create => {
-                    if let Ok(res) = room_create(room) {
-                        match res {
-                            ResultResponse::Ok { .. } => {
-                                connect_state.set(ConnectState::Wait);
-                            }
-                            ResultResponse::Err(m) => println!("{}", m),
-                        }
-                    } else {
-                        eprintln!("エラー")
+            commands.insert_resource(room_request(*section, room.clone(), setting.server.clone()));
+
+            set_status(
+                &mut status_query,
+                format!("connecting to {}", setting.server),
+            );
+            connect_state.set(ConnectState::Request);
+        }
+    }
+}
+
+// 部屋を作るか入るリクエストを送る
+fn room_request(section: ConnectSection, room: RoomRequest, server: String) -> RoomTask {
+    let room_id = room.room_id;
+    let task = Worker::spawn(move || {
+        match section {
+            ConnectSection::Create => room_create(&server, room),
+            ConnectSection::Enter => room_enter(&server, room),
+        }
+        .map_err(request_error)
+    });
+
+    RoomTask {
+        section,
+        task,
+        relay: Some(room_id),
+    }
+}
+
+// 部屋サーバーを通して見つけた相手なら、直接届かないときに中継してもらう
+fn set_relay(commands: &mut Commands, setting: &Setting, room_id: Option<u32>) {
+    match room_id.zip(setting.relay_addr()) {
+        Some((room_id, addr)) => commands.insert_resource(Relay { addr, room_id }),
+        None => commands.remove_resource::<Relay>(),
+    }
+}
+
+fn request_error(e: reqwest::Error) -> String {
+    if e.is_timeout() {
+        "server did not respond".to_string()
+    } else {
+        e.to_string()
+    }
+}
+
+fn game_mode(mode: OnlineMode) -> GameMode {
+    match mode {
+        OnlineMode::VS => GameMode::VS,
+        OnlineMode::Coop => GameMode::Coop,
+    }
+}
+
+fn mode_text(mode: OnlineMode) -> &'static str {
+    match mode {
+        OnlineMode::VS => "Mode: VS",
+        OnlineMode::Coop => "Mode: Co-op",
+    }
+}
+
+fn mode_button_system(
+    interaction: Query<&Interaction, (Changed<Interaction>, With<ModeButton>)>,
+    mut mode_text_query: Query<&mut Text, With<ModeText>>,
+    mut room: ResMut<RoomRequest>,
+) {
+    for interaction in &interaction {
+        if *interaction == Interaction::Pressed {
+            room.mode = match room.mode {
+                OnlineMode::VS => OnlineMode::Coop,
+                OnlineMode::Coop => OnlineMode::VS,
+            };
+            for mut text in &mut mode_text_query {
+                text.sections[0].value = mode_text(room.mode).to_string();
+            }
+        }
+    }
+}
+
+fn rules_text(setting: &Setting, rules: &Rules) -> String {
+    let name = setting
+        .rule_sets
+        .iter()
+        .find(|set| set.rules == *rules)
+        .map_or("Custom", |set| set.name.as_str());
+    format!("Rules: {}", name)
+}
+
+// 設定にあるルールを順番に選ぶ
+fn rules_button_system(
+    interaction: Query<&Interaction, (Changed<Interaction>, With<RulesButton>)>,
+    mut rules_text_query: Query<&mut Text, With<RulesText>>,
+    mut room: ResMut<RoomRequest>,
+    setting: Res<Setting>,
+) {
+    for interaction in &interaction {
+        if *interaction == Interaction::Pressed && !setting.rule_sets.is_empty() {
+            let next = setting
+                .rule_sets
+                .iter()
+                .position(|set| set.rules == room.rules)
+                .map_or(0, |i| (i + 1) % setting.rule_sets.len());
+            room.rules = setting.rule_sets[next].rules.clone();
+
+            for mut text in &mut rules_text_query {
+                text.sections[0].value = rules_text(&setting, &room.rules);
+            }
+        }
+    }
+}
+
+// サーバーの返事を受け取る
+fn room_task_system(
+    mut commands: Commands,
+    room_task: Res<RoomTask>,
+    mut status_query: Query<&mut Text, With<StatusText>>,
+    mut room: ResMut<RoomRequest>,
+    setting: Res<Setting>,
+    mut connect_state: ResMut<NextState<ConnectState>>,
+    mut game_state: ResMut<NextState<GameMode>>,
+) {
+    let Some(result) = room_task.task.poll() else {
+        return;
+    };
+    commands.remove_resource::<RoomTask>();
+
+    match result {
+        Ok(ResultResponse::Ok {
+            message,
+            user,
+            mode,
+            rules,
+            key,
+            token,
+        }) => {
+            set_status(&mut status_query, message);
+            match (room_task.section, user, key) {
+                (ConnectSection::Create, _, key) => {
+                    match key {
+                        Some(key) => commands.insert_resource(key),
+                        None => commands.remove_resource::<SessionKey>(),
                     }
+                    room.token = token;
+                    commands.insert_resource(Hosting::Server);
+                    connect_state.set(ConnectState::Wait);
                 }
-                ConnectSection::Enter => {
-                    if let Ok(res) = room_enter(room) {
-                        match res {
-                            ResultResponse::Ok { user, .. } => {
-                                commands.insert_resource(user.unwrap());
-
-                                connect_state.set(ConnectState::Disabled);
-                                game_state.set(GameMode::VS);
-                            }
-                            ResultResponse::Err(m) => println!("{}", m),
-                        }
-                    } else {
-                        eprintln!("エラー")
-                    }
+                (ConnectSection::Enter, Some(user), Some(key)) => {
+                    let relay = room_task
+                        .relay
+                        .filter(|_| user.capabilities.contains(&Capability::Relay));
+                    set_relay(&mut commands, &setting, relay);
+                    commands.insert_resource(user);
+                    commands.insert_resource(key);
+                    commands.insert_resource(rules);
+                    commands.insert_resource(Role::Guest);
+
+                    connect_state.set(ConnectState::Disabled);
+                    game_state.set(game_mode(mode));
+                }
+                (ConnectSection::Enter, None, _) => {
+                    set_status(&mut status_query, "host not found");
+                    connect_state.set(ConnectState::Disabled);
+                }
+                (ConnectSection::Enter, Some(_), None) => {
+                    set_status(&mut status_query, "server did not send a session key");
+                    connect_state.set(ConnectState::Disabled);
                 }
             }
         }
+        Ok(ResultResponse::Err(m)) | Err(m) => {
+            set_status(&mut status_query, m);
+            connect_state.set(ConnectState::Disabled);
+        }
     }
 }
 
 fn input_event(
     text_input_query: Query<(Entity, &InfoSection)>,
+    mut server_text_query: Query<&mut Text, With<ServerText>>,
     mut event: EventReader<TextInputSubmitEvent>,
     mut room: ResMut<RoomRequest>,
+    mut setting: ResMut<Setting>,
 ) {
     for event in event.read() {
         for (entity, section) in &text_input_query {
             if entity == event.entity {
                 let value = event.value.clone();
                 match *section {
+                    InfoSection::Server => {
+                        setting.set_server(&value);
+                        setting.save();
+                        for mut text in &mut server_text_query {
+                            text.sections[1].value = setting.server.clone();
+                        }
+                    }
                     InfoSection::Name => room.user.name = value,
                     InfoSection::RoomId => {
                         if let Ok(value) = value.trim().parse() {
@@ -325,9 +1105,71 @@ fn input_event(
                         }
                     }
                 }
-                println!("{:#?}", room);
                 return;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn handshake_rejects_other_version() {
+        let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // 違うバージョンの入る側
+        let guest = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let hello = Hello {
+                version: PROTOCOL_VERSION + 1,
+                user: User::new("guest", LOCALHOST),
+                key: Some(SessionKey([1; 32])),
+                tick_rate: None,
+            };
+            write_handshake(&mut stream, &Handshake::Hello(hello)).unwrap();
+            read_handshake(&mut stream).unwrap()
+        });
+
+        let (socket, _) = listener.accept().unwrap();
+        let host = User::new("host", LOCALHOST);
+        let e = handshake(socket, &host, &Rules::default(), None).unwrap_err();
+        let reason = version_mismatch(PROTOCOL_VERSION, PROTOCOL_VERSION + 1);
+        assert_eq!(e.to_string(), reason);
+        assert!(matches!(
+            guest.join().unwrap(),
+            Handshake::Reject(rejected) if rejected == reason
+        ));
+    }
+
+    #[test]
+    fn handshake_rejects_too_low_tick_rate() {
+        let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // 最小のティックレートも受け取れない入る側
+        let guest = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let hello = Hello {
+                version: PROTOCOL_VERSION,
+                user: User {
+                    max_tick_rate: MIN_TICK_RATE - 1,
+                    ..User::new("guest", LOCALHOST)
+                },
+                key: Some(SessionKey([1; 32])),
+                tick_rate: None,
+            };
+            write_handshake(&mut stream, &Handshake::Hello(hello)).unwrap();
+            read_handshake(&mut stream).unwrap()
+        });
+
+        let (socket, _) = listener.accept().unwrap();
+        let host = User::new("host", LOCALHOST);
+        assert!(handshake(socket, &host, &Rules::default(), None).is_err());
+        assert!(matches!(guest.join().unwrap(), Handshake::Reject(_)));
+    }
+}
@@ -0,0 +1,404 @@
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use bevy::{prelude::*, time::common_conditions::on_timer};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+
+use crate::game::{
+    notify_host, GameMode, Hello, OnlineMode, ResultResponse, RoomRequest, Rules, Server,
+    SessionKey, User, PROTOCOL_VERSION,
+};
+use crate::FontResource;
+
+use super::{
+    host_listen, set_status, ConnectSection, ConnectState, Hosting, RoomTask, StatusText, Worker,
+};
+
+// ホストがLANに知らせるポート
+const DISCOVERY_PORT: u16 = 8889;
+// 知らせる間隔
+const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+// これより長く届かないホストは一覧から消す
+const HOST_EXPIRE: Duration = Duration::from_secs(4);
+const MAX_BEACON_SIZE: usize = 512;
+
+const LIST_WIDTH: Val = Val::Px(500.);
+const LIST_HEIGHT: Val = Val::Px(120.);
+const ROW_HEIGHT: Val = Val::Px(40.);
+
+pub struct LanPlugin;
+
+impl Plugin for LanPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(GameMode::Connect), lan_listen)
+            .add_systems(OnExit(GameMode::Connect), lan_close)
+            .add_systems(OnEnter(ConnectState::Wait), beacon_setup)
+            .add_systems(OnExit(ConnectState::Wait), beacon_close)
+            .add_systems(
+                Update,
+                beacon_send
+                    .run_if(resource_exists::<BeaconSocket>())
+                    .run_if(on_timer(BEACON_INTERVAL))
+                    .run_if(in_state(ConnectState::Wait)),
+            )
+            .add_systems(
+                Update,
+                (
+                    lan_recv,
+                    lan_list_update,
+                    lan_button_system.run_if(resource_exists::<Server>()),
+                    host_lan_button_system.run_if(resource_exists::<Server>()),
+                )
+                    .run_if(in_state(GameMode::Connect))
+                    .run_if(in_state(ConnectState::Disabled)),
+            );
+    }
+}
+
+/// ホストが相手を待っている間に送り続ける情報
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Beacon {
+    version: u8,
+    user: User,
+    mode: OnlineMode,
+    rules: Rules,
+}
+
+// ホストが知らせるためのソケット
+#[derive(Resource)]
+struct BeaconSocket(UdpSocket);
+
+// 知らせを受け取るソケット
+#[derive(Resource)]
+struct LanListener(UdpSocket);
+
+// 見つかったホストと最後に届いた時刻
+#[derive(Resource, Default)]
+struct LanHosts(Vec<(Beacon, Instant)>);
+
+#[derive(Component)]
+struct LanList;
+
+// ホストのアドレスと待っているポート
+#[derive(Component)]
+struct LanHostButton(IpAddr, u16);
+
+// 部屋サーバーを使わずにLANで相手を待つ
+#[derive(Component)]
+pub(super) struct HostLanButton;
+
+pub(super) fn spawn_lan_list(parent: &mut ChildBuilder) {
+    parent.spawn((
+        NodeBundle {
+            style: Style {
+                width: LIST_WIDTH,
+                height: LIST_HEIGHT,
+                flex_direction: FlexDirection::Column,
+                overflow: Overflow::clip_y(),
+                border: UiRect::all(Val::Px(3.)),
+                margin: UiRect::vertical(Val::Px(10.)),
+                ..default()
+            },
+            border_color: BorderColor(Color::WHITE),
+            ..default()
+        },
+        LanList,
+    ));
+}
+
+// 同じPCで2つ起動しても両方に届くように、ポートを共有して開ける
+fn bind_discovery(port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+fn lan_listen(mut commands: Commands) {
+    match bind_discovery(DISCOVERY_PORT) {
+        Ok(socket) => commands.insert_resource(LanListener(socket)),
+        Err(e) => eprintln!("LAN discovery: {}", e),
+    }
+    commands.init_resource::<LanHosts>();
+}
+
+fn lan_close(mut commands: Commands) {
+    commands.remove_resource::<LanListener>();
+    commands.remove_resource::<LanHosts>();
+}
+
+fn beacon_setup(mut commands: Commands, hosting: Res<Hosting>) {
+    if *hosting != Hosting::Lan {
+        return;
+    }
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| socket.set_broadcast(true).map(|_| socket));
+
+    match socket {
+        Ok(socket) => commands.insert_resource(BeaconSocket(socket)),
+        Err(e) => eprintln!("LAN beacon: {}", e),
+    }
+}
+
+fn beacon_close(mut commands: Commands) {
+    commands.remove_resource::<BeaconSocket>();
+}
+
+// 相手を待っていることをLAN全体に知らせる
+fn beacon_send(socket: Res<BeaconSocket>, room: Res<RoomRequest>) {
+    let beacon = Beacon {
+        version: PROTOCOL_VERSION,
+        user: room.user.clone(),
+        mode: room.mode,
+        rules: room.rules.clone(),
+    };
+
+    match serde_json::to_vec(&beacon) {
+        Ok(buf) => {
+            if let Err(e) = socket
+                .0
+                .send_to(&buf, (Ipv4Addr::BROADCAST, DISCOVERY_PORT))
+            {
+                eprintln!("beacon: {}", e);
+            }
+        }
+        Err(e) => eprintln!("beacon: {}", e),
+    }
+}
+
+// 届いた知らせで一覧を更新する
+fn lan_recv(
+    listener: Option<Res<LanListener>>,
+    mut hosts: ResMut<LanHosts>,
+    room: Res<RoomRequest>,
+) {
+    let mut changed = false;
+    let list = &mut hosts.bypass_change_detection().0;
+
+    if let Some(listener) = listener {
+        let mut buf = [0; MAX_BEACON_SIZE];
+        loop {
+            let (size, addr) = match listener.0.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("LAN discovery: {}", e);
+                    break;
+                }
+            };
+            let Ok(mut beacon) = serde_json::from_slice::<Beacon>(&buf[..size]) else {
+                continue;
+            };
+            if beacon.version != PROTOCOL_VERSION {
+                continue;
+            }
+            // 自分が送ったもの
+            if addr.ip() == room.user.ip && beacon.user.port == room.user.port {
+                continue;
+            }
+            // 実際に送ってきたアドレスにつなぐ
+            beacon.user.ip = addr.ip();
+
+            let key = (beacon.user.ip, beacon.user.port);
+            match list
+                .iter_mut()
+                .find(|(host, _)| (host.user.ip, host.user.port) == key)
+            {
+                Some((host, seen)) => {
+                    changed |= host.user.name != beacon.user.name || host.mode != beacon.mode;
+                    *host = beacon;
+                    *seen = Instant::now();
+                }
+                None => {
+                    list.push((beacon, Instant::now()));
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let len = list.len();
+    list.retain(|(_, seen)| seen.elapsed() < HOST_EXPIRE);
+    changed |= list.len() != len;
+
+    if changed {
+        hosts.set_changed();
+    }
+}
+
+// 一覧が変わったらボタンを作り直す
+fn lan_list_update(
+    mut commands: Commands,
+    hosts: Res<LanHosts>,
+    list_query: Query<Entity, With<LanList>>,
+    font: Res<FontResource>,
+) {
+    if !hosts.is_changed() {
+        return;
+    }
+    let Ok(list) = list_query.get_single() else {
+        return;
+    };
+
+    let text_style = TextStyle {
+        font: font.0.clone(),
+        font_size: 28.,
+        color: Color::BLACK,
+    };
+
+    commands
+        .entity(list)
+        .despawn_descendants()
+        .with_children(|parent| {
+            if hosts.0.is_empty() {
+                parent.spawn(TextBundle::from_section(
+                    "no LAN hosts",
+                    TextStyle {
+                        color: Color::WHITE,
+                        ..text_style
+                    },
+                ));
+                return;
+            }
+            for (host, _) in &hosts.0 {
+                parent
+                    .spawn((
+                        ButtonBundle {
+                            style: Style {
+                                width: Val::Percent(100.),
+                                height: ROW_HEIGHT,
+                                flex_shrink: 0.,
+                                padding: UiRect::horizontal(Val::Px(10.)),
+                                margin: UiRect::bottom(Val::Px(3.)),
+                                align_items: AlignItems::Center,
+                                ..default()
+                            },
+                            ..default()
+                        },
+                        LanHostButton(host.user.ip, host.user.port),
+                    ))
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle::from_section(
+                            host_text(host),
+                            text_style.clone(),
+                        ));
+                    });
+            }
+        });
+}
+
+fn host_text(host: &Beacon) -> String {
+    let mode = match host.mode {
+        OnlineMode::VS => "VS",
+        OnlineMode::Coop => "Co-op",
+    };
+
+    format!(
+        "LAN  {}  {}  {}:{}",
+        host.user.name, mode, host.user.ip, host.user.port
+    )
+}
+
+// 押したホストに直接つなぐ
+fn lan_button_system(
+    mut commands: Commands,
+    interaction: Query<(&Interaction, &LanHostButton), Changed<Interaction>>,
+    mut status_query: Query<&mut Text, With<StatusText>>,
+    hosts: Res<LanHosts>,
+    room: Res<RoomRequest>,
+    mut connect_state: ResMut<NextState<ConnectState>>,
+) {
+    for (interaction, button) in &interaction {
+        if *interaction != Interaction::Pressed {
+            continue;
+        }
+        let Some((host, _)) = hosts
+            .0
+            .iter()
+            .find(|(host, _)| (host.user.ip, host.user.port) == (button.0, button.1))
+        else {
+            continue;
+        };
+
+        let host = host.clone();
+        // 部屋サーバーがないので鍵は入る側が作る
+        let key = SessionKey::generate();
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            user: room.user.clone(),
+            key: Some(key),
+            tick_rate: None,
+        };
+        let task = Worker::spawn(move || {
+            notify_host(&host.user, hello)
+                .map(|reply| ResultResponse::Ok {
+                    message: format!("joined {}", reply.user.name),
+                    // 知らせのアドレスは実際に届いたもの、ポートはホストが答えたものを使う
+                    user: Some(User {
+                        ip: host.user.ip,
+                        ..reply.user
+                    }),
+                    mode: host.mode,
+                    // ティックレートはホストが決めたもの
+                    rules: Rules {
+                        tick_rate: reply.tick_rate.unwrap_or(host.rules.tick_rate),
+                        ..host.rules
+                    },
+                    key: Some(key),
+                    token: None,
+                })
+                .map_err(|e| format!("could not join {}: {}", host.user.name, e))
+        });
+        commands.insert_resource(RoomTask {
+            section: ConnectSection::Enter,
+            task,
+            relay: None,
+        });
+
+        set_status(
+            &mut status_query,
+            format!("connecting to {}:{}", button.0, button.1),
+        );
+        connect_state.set(ConnectState::Request);
+    }
+}
+
+fn host_lan_button_system(
+    mut commands: Commands,
+    interaction: Query<&Interaction, (Changed<Interaction>, With<HostLanButton>)>,
+    mut status_query: Query<&mut Text, With<StatusText>>,
+    mut room: ResMut<RoomRequest>,
+    mut connect_state: ResMut<NextState<ConnectState>>,
+) {
+    for interaction in &interaction {
+        if *interaction == Interaction::Pressed {
+            if let Err(e) = host_listen(&mut commands, &mut room) {
+                set_status(&mut status_query, e.to_string());
+                continue;
+            }
+            commands.insert_resource(Hosting::Lan);
+
+            set_status(&mut status_query, "hosting on LAN");
+            connect_state.set(ConnectState::Wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovery_port_is_shared() {
+        let first = bind_discovery(0).unwrap();
+        let port = first.local_addr().unwrap().port();
+        let second = bind_discovery(port).unwrap();
+        assert_eq!(second.local_addr().unwrap().port(), port);
+    }
+}
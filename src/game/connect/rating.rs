@@ -0,0 +1,95 @@
+use bevy::prelude::*;
+
+use crate::game::{player_rating, GameMode, Rating, RoomRequest};
+use crate::setting::Setting;
+
+use super::{request_error, ConnectState, Worker};
+
+/// 入力した名前のレーティングを部屋サーバーに聞いて表示する
+pub struct RatingPlugin;
+
+impl Plugin for RatingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnExit(GameMode::Connect), rating_exit)
+            .add_systems(
+                Update,
+                (rating_request, rating_update)
+                    .run_if(in_state(GameMode::Connect))
+                    .run_if(in_state(ConnectState::Disabled)),
+            );
+    }
+}
+
+#[derive(Resource)]
+struct RatingTask(Worker<Rating>);
+
+// 最後に聞いた名前 (接続画面を開くたびに聞き直す)
+#[derive(Component, Default)]
+struct RatingText {
+    name: Option<String>,
+}
+
+pub(super) fn spawn_rating(parent: &mut ChildBuilder, text_style: TextStyle) {
+    parent.spawn((
+        TextBundle::from_section("", text_style),
+        RatingText::default(),
+    ));
+}
+
+// 名前が変わったら聞きに行く (前のが終わっていなければ待つ)
+fn rating_request(
+    mut commands: Commands,
+    setting: Res<Setting>,
+    room: Res<RoomRequest>,
+    task: Option<Res<RatingTask>>,
+    mut rating_query: Query<&mut RatingText>,
+) {
+    let name = &room.user.name;
+    if task.is_some() || name.is_empty() {
+        return;
+    }
+
+    for mut rating in &mut rating_query {
+        if rating.name.as_ref() == Some(name) {
+            continue;
+        }
+        rating.name = Some(name.clone());
+
+        let server = setting.server.clone();
+        let name = name.clone();
+        let task = Worker::spawn(move || player_rating(&server, &name).map_err(request_error));
+        commands.insert_resource(RatingTask(task));
+    }
+}
+
+fn rating_update(
+    mut commands: Commands,
+    task: Option<Res<RatingTask>>,
+    room: Res<RoomRequest>,
+    mut rating_query: Query<&mut Text, With<RatingText>>,
+) {
+    let Some(result) = task.and_then(|task| task.0.poll()) else {
+        return;
+    };
+    commands.remove_resource::<RatingTask>();
+
+    // レーティングに対応していないサーバーなら何も出さない
+    let value = match result {
+        Ok(rating) if rating.name == room.user.name => format!(
+            "rating: {:.0}  ({}W {}L {}D)",
+            rating.rating, rating.wins, rating.losses, rating.draws
+        ),
+        Ok(_) => return,
+        Err(e) => {
+            eprintln!("rating: {}", e);
+            String::new()
+        }
+    };
+    for mut text in &mut rating_query {
+        text.sections[0].value = value.clone();
+    }
+}
+
+fn rating_exit(mut commands: Commands) {
+    commands.remove_resource::<RatingTask>();
+}
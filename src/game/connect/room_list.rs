@@ -0,0 +1,313 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bevy::input::mouse::{MouseScrollUnit, MouseWheel};
+use bevy::{prelude::*, time::common_conditions::on_timer};
+
+use crate::game::{
+    match_list, room_list, Capability, GameMode, MatchInfo, OnlineMode, RoomInfo, RoomRequest,
+    Server, Spectator,
+};
+use crate::setting::Setting;
+use crate::FontResource;
+
+use super::{
+    request_error, room_request, set_status, ConnectSection, ConnectState, StatusText, Worker,
+};
+
+// 一覧を更新する間隔
+const REFRESH_INTERVAL: Duration = Duration::from_secs(3);
+
+const LIST_WIDTH: Val = Val::Px(500.);
+const LIST_HEIGHT: Val = Val::Px(180.);
+const ROW_HEIGHT: Val = Val::Px(40.);
+// ホイール1行分のスクロール量
+const SCROLL_LINE_HEIGHT: f32 = 20.;
+
+pub struct RoomListPlugin;
+
+impl Plugin for RoomListPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(GameMode::Connect), room_list_request)
+            .add_systems(
+                Update,
+                (
+                    room_list_request.run_if(on_timer(REFRESH_INTERVAL)),
+                    room_list_update,
+                    room_list_scroll,
+                    room_button_system.run_if(resource_exists::<Server>()),
+                    watch_button_system.run_if(resource_exists::<Server>()),
+                )
+                    .run_if(in_state(GameMode::Connect))
+                    .run_if(in_state(ConnectState::Disabled)),
+            );
+    }
+}
+
+// 入れる部屋と観戦できる対戦
+type Lists = (Vec<RoomInfo>, Vec<MatchInfo>);
+
+#[derive(Resource)]
+struct RoomListTask(Worker<Lists>);
+
+// 部屋のボタンを並べるところ
+#[derive(Component, Default)]
+struct RoomList {
+    position: f32,
+}
+
+#[derive(Component)]
+struct RoomButton(u32);
+
+#[derive(Component)]
+struct WatchButton(MatchInfo);
+
+pub(super) fn spawn_room_list(parent: &mut ChildBuilder) {
+    parent
+        .spawn(NodeBundle {
+            style: Style {
+                width: LIST_WIDTH,
+                height: LIST_HEIGHT,
+                flex_direction: FlexDirection::Column,
+                overflow: Overflow::clip_y(),
+                border: UiRect::all(Val::Px(3.)),
+                margin: UiRect::vertical(Val::Px(10.)),
+                ..default()
+            },
+            border_color: BorderColor(Color::WHITE),
+            ..default()
+        })
+        .with_children(|parent| {
+            parent.spawn((
+                NodeBundle {
+                    style: Style {
+                        width: Val::Percent(100.),
+                        flex_direction: FlexDirection::Column,
+                        ..default()
+                    },
+                    ..default()
+                },
+                RoomList::default(),
+            ));
+        });
+}
+
+// 一覧を取りに行く (前のが終わっていなければ待つ)
+fn room_list_request(
+    mut commands: Commands,
+    setting: Res<Setting>,
+    task: Option<Res<RoomListTask>>,
+) {
+    if task.is_some() {
+        return;
+    }
+
+    let server = setting.server.clone();
+    let task = Worker::spawn(move || {
+        let rooms = room_list(&server).map_err(request_error)?;
+        // 観戦に対応していないサーバーでも部屋は出す
+        let matches = match_list(&server)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|info| info.host.capabilities.contains(&Capability::Spectate))
+            .collect();
+        Ok((rooms, matches))
+    });
+    commands.insert_resource(RoomListTask(task));
+}
+
+// 届いた一覧でボタンを作り直す
+fn room_list_update(
+    mut commands: Commands,
+    task: Option<Res<RoomListTask>>,
+    list_query: Query<Entity, With<RoomList>>,
+    font: Res<FontResource>,
+) {
+    let Some(rooms) = task.and_then(|task| task.0.poll()) else {
+        return;
+    };
+    commands.remove_resource::<RoomListTask>();
+
+    let Ok(list) = list_query.get_single() else {
+        return;
+    };
+
+    let text_style = TextStyle {
+        font: font.0.clone(),
+        font_size: 28.,
+        color: Color::BLACK,
+    };
+    let row_style = Style {
+        width: Val::Percent(100.),
+        height: ROW_HEIGHT,
+        flex_shrink: 0.,
+        padding: UiRect::horizontal(Val::Px(10.)),
+        margin: UiRect::bottom(Val::Px(3.)),
+        align_items: AlignItems::Center,
+        ..default()
+    };
+
+    commands
+        .entity(list)
+        .despawn_descendants()
+        .with_children(|parent| match rooms {
+            Ok((rooms, matches)) if rooms.is_empty() && matches.is_empty() => {
+                parent.spawn(TextBundle::from_section(
+                    "no open rooms",
+                    TextStyle {
+                        color: Color::WHITE,
+                        ..text_style
+                    },
+                ));
+            }
+            Ok((rooms, matches)) => {
+                for room in rooms {
+                    parent
+                        .spawn((
+                            ButtonBundle {
+                                style: row_style.clone(),
+                                ..default()
+                            },
+                            RoomButton(room.room_id),
+                        ))
+                        .with_children(|parent| {
+                            parent.spawn(TextBundle::from_section(
+                                room_text(&room),
+                                text_style.clone(),
+                            ));
+                        });
+                }
+                // 観戦できる対戦は部屋の後に色を変えて出す
+                for info in matches {
+                    let text = format!(
+                        "Watch #{}  {} vs {}",
+                        info.room_id, info.host.name, info.guest
+                    );
+                    parent
+                        .spawn((
+                            ButtonBundle {
+                                style: row_style.clone(),
+                                background_color: Color::SILVER.into(),
+                                ..default()
+                            },
+                            WatchButton(info),
+                        ))
+                        .with_children(|parent| {
+                            parent.spawn(TextBundle::from_section(text, text_style.clone()));
+                        });
+                }
+            }
+            Err(e) => {
+                parent.spawn(TextBundle::from_section(
+                    e,
+                    TextStyle {
+                        color: Color::RED,
+                        ..text_style
+                    },
+                ));
+            }
+        });
+}
+
+fn room_text(room: &RoomInfo) -> String {
+    let mode = match room.mode {
+        OnlineMode::VS => "VS",
+        OnlineMode::Coop => "Co-op",
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let age = now.saturating_sub(room.created);
+    let age = match age {
+        0..=59 => format!("{}s", age),
+        60..=3599 => format!("{}m", age / 60),
+        _ => format!("{}h", age / 3600),
+    };
+
+    // VSのルールは標準と違うところだけ
+    let mut rules = String::new();
+    if room.mode == OnlineMode::VS {
+        if let Some(limit) = room.rules.time_limit {
+            rules += &format!(" {}s", limit);
+        }
+        if room.rules.best_of > 1 {
+            rules += &format!(" Bo{}", room.rules.best_of);
+        }
+    }
+
+    format!(
+        "#{}  {}  {}{}  {} ago",
+        room.room_id, room.host, mode, rules, age
+    )
+}
+
+fn room_list_scroll(
+    mut mouse_wheel_event: EventReader<MouseWheel>,
+    mut list_query: Query<(&mut RoomList, &mut Style, &Parent, &Node)>,
+    node_query: Query<&Node>,
+) {
+    for event in mouse_wheel_event.read() {
+        for (mut list, mut style, parent, list_node) in &mut list_query {
+            let Ok(container) = node_query.get(parent.get()) else {
+                continue;
+            };
+            let max_scroll = (list_node.size().y - container.size().y).max(0.);
+            let dy = match event.unit {
+                MouseScrollUnit::Line => event.y * SCROLL_LINE_HEIGHT,
+                MouseScrollUnit::Pixel => event.y,
+            };
+
+            list.position = (list.position + dy).clamp(-max_scroll, 0.);
+            style.top = Val::Px(list.position);
+        }
+    }
+}
+
+// 押した部屋に入る
+fn room_button_system(
+    mut commands: Commands,
+    interaction: Query<(&Interaction, &RoomButton), Changed<Interaction>>,
+    mut status_query: Query<&mut Text, With<StatusText>>,
+    mut room: ResMut<RoomRequest>,
+    setting: Res<Setting>,
+    mut connect_state: ResMut<NextState<ConnectState>>,
+) {
+    for (interaction, button) in &interaction {
+        if *interaction == Interaction::Pressed {
+            room.room_id = button.0;
+            commands.insert_resource(room_request(
+                ConnectSection::Enter,
+                room.clone(),
+                setting.server.clone(),
+            ));
+
+            set_status(&mut status_query, format!("entering room {}", button.0));
+            connect_state.set(ConnectState::Request);
+        }
+    }
+}
+
+// 押した対戦を観戦する (ホストにつなぐ)
+fn watch_button_system(
+    mut commands: Commands,
+    interaction: Query<(&Interaction, &WatchButton), Changed<Interaction>>,
+    mut status_query: Query<&mut Text, With<StatusText>>,
+    mut game_state: ResMut<NextState<GameMode>>,
+) {
+    for (interaction, button) in &interaction {
+        if *interaction == Interaction::Pressed {
+            let info = &button.0;
+            commands.insert_resource(Spectator {
+                host: info.host.name.clone(),
+                guest: info.guest.clone(),
+            });
+            commands.insert_resource(info.host.clone());
+            commands.insert_resource(info.rules.clone());
+
+            set_status(
+                &mut status_query,
+                format!("watching {} vs {}", info.host.name, info.guest),
+            );
+            game_state.set(GameMode::VS);
+        }
+    }
+}
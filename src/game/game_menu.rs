@@ -82,6 +82,28 @@ pub fn game_menu_setup(mut commands: Commands, font: Res<FontResource>) {
                         .with_children(|parent| {
                             parent.spawn(TextBundle::from_section("Online Play", text_style(60.)));
                         });
+                    parent
+                        .spawn((
+                            ButtonBundle {
+                                style: button_style.clone(),
+                                ..default()
+                            },
+                            GameMode::Cpu,
+                        ))
+                        .with_children(|parent| {
+                            parent.spawn(TextBundle::from_section("VS CPU", text_style(60.)));
+                        });
+                    parent
+                        .spawn((
+                            ButtonBundle {
+                                style: button_style.clone(),
+                                ..default()
+                            },
+                            GameMode::HotSeat,
+                        ))
+                        .with_children(|parent| {
+                            parent.spawn(TextBundle::from_section("VS Local", text_style(60.)));
+                        });
                 });
         });
 }
@@ -0,0 +1,842 @@
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use socket2::Type;
+
+use super::{
+    bind_dual_stack, relay_header, AttackType, Rules, SeqWindow, SessionKey, User,
+    DEFAULT_TICK_RATE, MAX_TICK_RATE, MIN_TICK_RATE, TAG_SIZE,
+};
+use crate::setting::{RelayMode, Setting};
+
+// 形式を変えたら上げる
+pub const PROTOCOL_VERSION: u8 = 15;
+// これより大きいデータグラムは読まない
+const MAX_PACKET_SIZE: usize = 512;
+// 直接つないでから何も届かなければ中継に切り替える
+const RELAY_FALLBACK: Duration = Duration::from_secs(2);
+// 観戦者の数の上限
+const MAX_SPECTATORS: usize = 8;
+// これより長く `Watch` が届かない観戦者には送らない
+const SPECTATOR_EXPIRE: Duration = Duration::from_secs(5);
+// `Ack` が返ってこない `Reliable` を送り直す間隔
+const RESEND_INTERVAL: Duration = Duration::from_millis(250);
+// 終わったあと `Ack` を待つ時間の上限
+const CLOSE_LINGER: Duration = Duration::from_secs(2);
+
+/// オンラインでやり取りするメッセージ
+///
+/// 座標は送る側から見た値で、VSでは受け取った側が反転させる
+///
+/// VSの当たり判定とHPはホストが決めて `HpState` で知らせる
+///
+/// VSのホストは観戦者に座標、攻撃、HPを `Spectate` に包んで送る
+#[derive(Event, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum NetMessage {
+    /// 自分の座標と送った時刻 (起動してからの秒数)
+    Position {
+        x: f32,
+        y: f32,
+        time: f64,
+    },
+    /// 攻撃を出した座標と種類
+    ///
+    /// VSでは1回に1つ送り、広がりは `AttackType::list` で決まる (協力プレイでは弾ごとに送る)
+    Fire {
+        x: f32,
+        y: f32,
+        attack: AttackType,
+    },
+    /// 自分のHP
+    Hp(isize),
+    /// VS: ホストが決めた両方のHP
+    HpState {
+        round: u32,
+        host: isize,
+        guest: isize,
+    },
+    /// 生存確認と往復時間の計測 (受け取ったら同じ番号の `Pong` を返す)
+    Ping(u32),
+    Pong(u32),
+    /// 対戦をやめた
+    Leave,
+    /// 協力プレイ: ホストが敵を作った
+    Wave {
+        wave: u32,
+        seed: u64,
+    },
+    /// 協力プレイ: 敵に攻撃を当てた
+    EnemyHit {
+        id: u32,
+        damage: isize,
+    },
+    /// 協力プレイ: 倒した敵の数
+    Kill(usize),
+    /// 協力プレイ: ホストの敵が攻撃した (ゲストは届いたときにいる敵から攻撃を出す)
+    EnemyFire,
+    /// 協力プレイ: どちらかがやられた
+    GameOver,
+    /// VS: 決着がついたときの自分の状態 (やられたか、時間切れか)
+    Final {
+        round: u32,
+        down: bool,
+        time_up: bool,
+    },
+    /// VS: 再戦の申し込み (次の試合の番号)
+    Rematch(u32),
+    /// VS: 再戦の申し込みを受け取った (もう始めている)
+    RematchAck(u32),
+    /// VS: ゲストが試合の始まりを待っている
+    Ready(u32),
+    /// VS: ホストがカウントダウンを始めた
+    Start(u32),
+    /// 観戦者がホストに送り続ける
+    Watch,
+    /// 観戦者へ: ホストかゲストのメッセージ (座標はその人から見た値)
+    Spectate {
+        guest: bool,
+        message: Box<NetMessage>,
+    },
+    /// 届くまで送り直すもの (受け取った側は `Ack` を返し、番号の順に渡す)
+    Reliable {
+        id: u32,
+        message: Box<NetMessage>,
+    },
+    /// `Reliable` を受け取った
+    Ack(u32),
+}
+
+impl NetMessage {
+    // 観戦者にも送るもの
+    fn is_spectated(&self) -> bool {
+        matches!(
+            self,
+            NetMessage::Position { .. }
+                | NetMessage::Fire { .. }
+                | NetMessage::HpState { .. }
+                | NetMessage::Leave
+        )
+    }
+}
+
+/// 部屋を作った側か入った側か
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Host,
+    Guest,
+}
+
+/// 対戦には加わらずに観戦している (ホストとゲストの名前)
+#[derive(Resource, Debug, Clone)]
+pub struct Spectator {
+    pub host: String,
+    pub guest: String,
+}
+
+/// 部屋サーバーで相手を見つけたときの中継先
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct Relay {
+    pub addr: SocketAddr,
+    pub room_id: u32,
+}
+
+/// データグラムの中身 (鍵があれば後ろに `TAG_SIZE` バイトのMACが付く)
+///
+/// 先頭の3つは部屋サーバーの中継も読むので、並びを変えない
+#[derive(Serialize, Deserialize)]
+struct Packet {
+    version: u8,
+    // つなぐたびに選び直す (番号はセッションごとに1から数える)
+    session: u32,
+    seq: u32,
+    message: NetMessage,
+}
+
+// 相手の `Reliable` を番号の順に渡す (先に届いたものは前のものが届くまで待たせる)
+#[derive(Default)]
+struct ReliableInbox {
+    // 相手のセッション (変わったら番号は1から数え直す)
+    session: Option<u32>,
+    // 次に渡す番号
+    next: u32,
+    ahead: BTreeMap<u32, NetMessage>,
+}
+
+impl ReliableInbox {
+    // 渡せるようになったものを返す
+    fn push(&mut self, session: u32, id: u32, message: NetMessage) -> Vec<NetMessage> {
+        if self.session != Some(session) {
+            *self = ReliableInbox {
+                session: Some(session),
+                next: 1,
+                ahead: BTreeMap::new(),
+            };
+        }
+        // 送り直されたもの
+        if id < self.next {
+            return Vec::new();
+        }
+        self.ahead.entry(id).or_insert(message);
+
+        let mut ready = Vec::new();
+        while let Some(message) = self.ahead.remove(&self.next) {
+            ready.push(message);
+            self.next += 1;
+        }
+        ready
+    }
+}
+
+/// 受け取らずに捨てたデータグラムの数
+#[derive(Default, Debug, Clone, Copy)]
+pub struct NetStats {
+    pub received: usize,
+    pub malformed: usize,
+    pub version_mismatch: usize,
+    pub out_of_order: usize,
+    // MACが合わなかった
+    pub bad_tag: usize,
+}
+
+/// 相手とやり取りするソケット
+///
+/// 観戦者も同じポートに送ってくるので、相手のアドレスで分ける
+#[derive(Resource)]
+pub struct Server {
+    socket: UdpSocket,
+    // IPv6のソケット (IPv4の相手にはIPv4射影アドレスで送る)
+    ipv6: bool,
+    // 相手 (中継しているときは部屋サーバー) のアドレス
+    peer: Option<SocketAddr>,
+    // 観戦者と最後に `Watch` が届いた時刻 (受け付けていなければNone)
+    spectators: Option<Vec<(SocketAddr, Instant)>>,
+    // 中継しているときに先頭に付けるもの
+    relay_header: Option<Vec<u8>>,
+    // 対戦の鍵と、自分がホストか (観戦者は鍵を持たない)
+    key: Option<(SessionKey, bool)>,
+    session: u32,
+    seq: u32,
+    window: SeqWindow,
+    // 最後に使った `Reliable` の番号
+    reliable_id: u32,
+    // `Ack` を待っている `Reliable` と最後に送った時刻
+    unacked: Vec<(u32, NetMessage, Instant)>,
+    inbox: ReliableInbox,
+    // 最後に相手から届いた時刻
+    last_recv: Instant,
+    // 座標や状態を送る間隔 (ハンドシェイクで決めたティックレート)
+    tick: Timer,
+    pub stats: NetStats,
+}
+
+fn tick_timer(rate: u32) -> Timer {
+    let interval = Duration::from_secs_f64(1. / rate.clamp(MIN_TICK_RATE, MAX_TICK_RATE) as f64);
+    Timer::new(interval, TimerMode::Repeating)
+}
+
+#[inline]
+fn options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(MAX_PACKET_SIZE as u64)
+}
+
+impl Server {
+    /// 空いているポートで待ち受ける (ポートは `User::net_port` で相手に知らせる)
+    /// IPv4とIPv6のどちらの相手とも使えるようにする
+    pub fn bind(ip: IpAddr) -> io::Result<Server> {
+        let socket: UdpSocket = bind_dual_stack(ip, Type::DGRAM)?.into();
+        socket.set_nonblocking(true)?;
+        let ipv6 = socket.local_addr()?.is_ipv6();
+
+        Ok(Server {
+            socket,
+            ipv6,
+            peer: None,
+            spectators: None,
+            relay_header: None,
+            key: None,
+            session: rand::random(),
+            seq: 0,
+            window: SeqWindow::default(),
+            reliable_id: 0,
+            unacked: Vec::new(),
+            inbox: ReliableInbox::default(),
+            last_recv: Instant::now(),
+            tick: tick_timer(DEFAULT_TICK_RATE),
+            stats: NetStats::default(),
+        })
+    }
+
+    /// 1秒に `rate` 回送る
+    pub fn set_tick_rate(&mut self, rate: u32) {
+        self.tick = tick_timer(rate);
+    }
+
+    pub fn port(&self) -> io::Result<u16> {
+        self.socket.local_addr().map(|addr| addr.port())
+    }
+
+    /// 相手のポートにつなぐ
+    pub fn connect(&mut self, opponent: &User) -> io::Result<()> {
+        self.peer = Some(canonical(SocketAddr::new(opponent.ip, opponent.net_port)));
+        self.relay_header = None;
+        self.session = rand::random();
+        self.seq = 0;
+        self.window = SeqWindow::default();
+        self.reliable_id = 0;
+        self.unacked.clear();
+        self.inbox = ReliableInbox::default();
+        self.last_recv = Instant::now();
+        Ok(())
+    }
+
+    /// 部屋サーバーを通して送るようにする
+    pub fn relay(&mut self, relay: &Relay, role: Role) -> io::Result<()> {
+        self.peer = Some(canonical(relay.addr));
+        self.relay_header = Some(relay_header(relay.room_id, role == Role::Host).to_vec());
+        // 切り替えてから届くまで待つ
+        self.last_recv = Instant::now();
+        Ok(())
+    }
+
+    /// 相手とのデータグラムにMACを付けて、MACが合わないものは捨てる
+    pub fn authenticate(&mut self, key: SessionKey, role: Role) {
+        self.key = Some((key, role == Role::Host));
+    }
+
+    pub fn is_relayed(&self) -> bool {
+        self.relay_header.is_some()
+    }
+
+    /// 観戦者を受け付ける (VSのホストだけ)
+    pub fn accept_spectators(&mut self) {
+        self.spectators.get_or_insert_with(Vec::new);
+    }
+
+    pub fn send(&mut self, message: NetMessage) {
+        let Some(peer) = self.peer else {
+            return;
+        };
+        if message.is_spectated() {
+            self.spectate(false, &message);
+        }
+
+        let buf = self
+            .encode(message, true)
+            .map(|buf| match &self.relay_header {
+                Some(header) => [header.as_slice(), &buf].concat(),
+                None => buf,
+            });
+        match buf {
+            Ok(buf) => {
+                if let Err(e) = self.socket.send_to(&buf, self.target(peer)) {
+                    eprintln!("send: {}", e);
+                }
+            }
+            Err(e) => eprintln!("encode: {}", e),
+        }
+    }
+
+    /// `Ack` が返ってくるまで送り直す
+    pub fn send_reliable(&mut self, message: NetMessage) {
+        self.reliable_id = self.reliable_id.wrapping_add(1);
+        let id = self.reliable_id;
+        self.unacked.push((id, message.clone(), Instant::now()));
+        self.send(NetMessage::Reliable {
+            id,
+            message: Box::new(message),
+        });
+    }
+
+    /// `Ack` が返ってこないまま `RESEND_INTERVAL` が過ぎたものを送り直す
+    pub fn resend(&mut self) {
+        let due: Vec<NetMessage> = self
+            .unacked
+            .iter_mut()
+            .filter(|(_, _, sent)| sent.elapsed() >= RESEND_INTERVAL)
+            .map(|(id, message, sent)| {
+                *sent = Instant::now();
+                NetMessage::Reliable {
+                    id: *id,
+                    message: Box::new(message.clone()),
+                }
+            })
+            .collect();
+        for message in due {
+            self.send(message);
+        }
+    }
+
+    // 観戦者に送る (中継はしない)
+    fn spectate(&mut self, guest: bool, message: &NetMessage) {
+        let Some(spectators) = &mut self.spectators else {
+            return;
+        };
+        spectators.retain(|(_, seen)| seen.elapsed() < SPECTATOR_EXPIRE);
+        if spectators.is_empty() {
+            return;
+        }
+        let addrs: Vec<SocketAddr> = spectators.iter().map(|(addr, _)| *addr).collect();
+
+        let message = NetMessage::Spectate {
+            guest,
+            message: Box::new(message.clone()),
+        };
+        // 観戦者は鍵を持っていないのでMACは付けない
+        match self.encode(message, false) {
+            Ok(buf) => {
+                for addr in addrs {
+                    if let Err(e) = self.socket.send_to(&buf, self.target(addr)) {
+                        eprintln!("spectate {}: {}", addr, e);
+                    }
+                }
+            }
+            Err(e) => eprintln!("encode: {}", e),
+        }
+    }
+
+    fn encode(&mut self, message: NetMessage, sign: bool) -> bincode::Result<Vec<u8>> {
+        self.seq = self.seq.wrapping_add(1);
+        let packet = Packet {
+            version: PROTOCOL_VERSION,
+            session: self.session,
+            seq: self.seq,
+            message,
+        };
+        let mut buf = options().serialize(&packet)?;
+        if let (true, Some((key, host))) = (sign, &self.key) {
+            let tag = key.sign(*host, &buf);
+            buf.extend_from_slice(&tag);
+        }
+        Ok(buf)
+    }
+
+    // 届いているデータグラムを全て読む
+    pub fn recv(&mut self) -> Vec<NetMessage> {
+        let mut messages = Vec::new();
+        let mut buf = [0; MAX_PACKET_SIZE + TAG_SIZE];
+
+        loop {
+            let (size, from) = match self.socket.recv_from(&mut buf) {
+                Ok((size, from)) => (size, canonical(from)),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("recv: {}", e);
+                    break;
+                }
+            };
+            if Some(from) != self.peer {
+                self.spectator_recv(from, &buf[..size]);
+                continue;
+            }
+            match self.decode(&buf[..size], from) {
+                Some(NetMessage::Reliable { id, message }) => {
+                    self.send(NetMessage::Ack(id));
+                    if let Some(session) = self.window.session() {
+                        messages.extend(self.inbox.push(session, id, *message));
+                    }
+                }
+                Some(NetMessage::Ack(id)) => self.unacked.retain(|(sent, _, _)| *sent != id),
+                Some(message) => {
+                    if message.is_spectated() {
+                        self.spectate(true, &message);
+                    }
+                    messages.push(message);
+                }
+                None => {}
+            }
+        }
+
+        messages
+    }
+
+    // 相手以外から届いたものは観戦の申し込みか、やめる知らせだけ受け取る
+    fn spectator_recv(&mut self, from: SocketAddr, buf: &[u8]) {
+        let Some(spectators) = &mut self.spectators else {
+            return;
+        };
+        if buf.first() != Some(&PROTOCOL_VERSION) {
+            return;
+        }
+        let Ok(packet) = options().deserialize::<Packet>(buf) else {
+            return;
+        };
+
+        let known = spectators.iter().position(|(addr, _)| *addr == from);
+        match (packet.message, known) {
+            (NetMessage::Watch, Some(i)) => spectators[i].1 = Instant::now(),
+            (NetMessage::Watch, None) if spectators.len() < MAX_SPECTATORS => {
+                println!("spectator joined: {}", from);
+                spectators.push((from, Instant::now()));
+            }
+            (NetMessage::Leave, Some(i)) => {
+                println!("spectator left: {}", from);
+                spectators.remove(i);
+            }
+            _ => {}
+        }
+    }
+
+    // IPv6のソケットからIPv4の相手に送るときは射影アドレスにする
+    fn target(&self, addr: SocketAddr) -> SocketAddr {
+        match addr.ip() {
+            IpAddr::V4(ip) if self.ipv6 => {
+                SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port())
+            }
+            _ => addr,
+        }
+    }
+
+    /// 相手から何も届いていない時間
+    pub fn silence(&self) -> Duration {
+        self.last_recv.elapsed()
+    }
+
+    fn decode(&mut self, buf: &[u8], from: SocketAddr) -> Option<NetMessage> {
+        // 先頭の1バイトがバージョン
+        if buf.first() != Some(&PROTOCOL_VERSION) {
+            self.stats.version_mismatch += 1;
+            return None;
+        }
+        // 相手が付けたMACを確かめる (送った向きも入っているので、跳ね返したものは通らない)
+        let body = match &self.key {
+            Some((key, host)) => {
+                let Some(body) = key.verify(!host, buf) else {
+                    self.stats.bad_tag += 1;
+                    eprintln!("rejected packet from {}: bad tag", from);
+                    return None;
+                };
+                body
+            }
+            None => buf,
+        };
+        let Ok(packet) = options().deserialize::<Packet>(body) else {
+            self.stats.malformed += 1;
+            return None;
+        };
+        if !self.window.accept(packet.session, packet.seq) {
+            self.stats.out_of_order += 1;
+            eprintln!(
+                "rejected packet from {}: session {:08x} seq {} is not new",
+                from, packet.session, packet.seq
+            );
+            return None;
+        }
+
+        self.last_recv = Instant::now();
+        self.stats.received += 1;
+
+        Some(packet.message)
+    }
+}
+
+// IPv4射影アドレスはIPv4にそろえて比べる
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+// 相手につなぐ (設定によっては最初から中継する)
+// 観戦者はホストにつなぐ
+pub fn net_connect(
+    mut server: ResMut<Server>,
+    opponent: Res<User>,
+    relay: Option<Res<Relay>>,
+    role: Option<Res<Role>>,
+    key: Option<Res<SessionKey>>,
+    rules: Option<Res<Rules>>,
+    setting: Res<Setting>,
+) {
+    if let (Some(key), Some(role)) = (&key, &role) {
+        server.authenticate(**key, **role);
+    }
+    if let Some(rules) = rules {
+        server.set_tick_rate(rules.tick_rate);
+    }
+    let result = match (relay, role) {
+        (Some(relay), Some(role)) if setting.relay == RelayMode::Always => {
+            server.relay(&relay, *role)
+        }
+        _ => server.connect(&opponent),
+    };
+    if let Err(e) = result {
+        eprintln!("connect: {}", e);
+    }
+}
+
+// 直接届かなければ中継に切り替える
+pub fn relay_fallback(
+    mut server: ResMut<Server>,
+    relay: Option<Res<Relay>>,
+    role: Option<Res<Role>>,
+    setting: Res<Setting>,
+) {
+    let (Some(relay), Some(role)) = (relay, role) else {
+        return;
+    };
+    if setting.relay == RelayMode::Off || server.is_relayed() || server.silence() < RELAY_FALLBACK {
+        return;
+    }
+
+    println!("switching to relay {}", relay.addr);
+    if let Err(e) = server.relay(&relay, *role) {
+        eprintln!("relay: {}", e);
+    }
+}
+
+// 送る時刻か数える (`on_net_tick` の前に動かす)
+// `Ack` が返ってこないものもここで送り直す
+pub fn net_tick(mut server: ResMut<Server>, time: Res<Time>) {
+    server.tick.tick(time.delta());
+    server.resend();
+}
+
+/// ゲームを抜けたあと、送った `Reliable` が届くまで少しの間だけ残しておくソケット
+#[derive(Resource)]
+pub struct Closing {
+    server: Server,
+    since: Instant,
+}
+
+/// `Server` を外して `Closing` にする (新しい `Server` を入れても邪魔にならない)
+pub fn close_server(commands: &mut Commands) {
+    commands.add(|world: &mut World| {
+        if let Some(server) = world.remove_resource::<Server>() {
+            world.insert_resource(Closing {
+                server,
+                since: Instant::now(),
+            });
+        }
+    });
+}
+
+// `Ack` が全て返ってくるか、`CLOSE_LINGER` が過ぎたら閉じる
+pub fn net_closing(mut commands: Commands, mut closing: ResMut<Closing>) {
+    let closing = &mut *closing;
+    let server = &mut closing.server;
+    server.recv();
+    server.resend();
+
+    if server.unacked.is_empty() || closing.since.elapsed() > CLOSE_LINGER {
+        commands.remove_resource::<Closing>();
+    }
+}
+
+/// ティックレートで座標や状態を送るシステムの条件
+pub fn on_net_tick(server: Option<Res<Server>>) -> bool {
+    server.is_some_and(|server| server.tick.just_finished())
+}
+
+// 受け取ったメッセージをイベントにする
+pub fn net_recv(mut server: ResMut<Server>, mut event: EventWriter<NetMessage>) {
+    event.send_batch(server.recv());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::game::NET_PORT;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn from() -> SocketAddr {
+        SocketAddr::new(LOCALHOST, NET_PORT)
+    }
+
+    #[test]
+    fn new_session_restarts_seq() {
+        let mut host = Server::bind(LOCALHOST).unwrap();
+        let mut guest = Server::bind(LOCALHOST).unwrap();
+
+        let old = guest.encode(NetMessage::Ping(1), true).unwrap();
+        assert_eq!(host.decode(&old, from()), Some(NetMessage::Ping(1)));
+        let next = guest.encode(NetMessage::Ping(2), true).unwrap();
+        assert_eq!(host.decode(&next, from()), Some(NetMessage::Ping(2)));
+
+        // つなぎ直すと番号は初めからになる
+        guest.connect(&User::new("host", LOCALHOST)).unwrap();
+        let fresh = guest.encode(NetMessage::Ping(3), true).unwrap();
+        assert_eq!(host.decode(&fresh, from()), Some(NetMessage::Ping(3)));
+
+        // 前のセッションのものは受け取らない
+        assert_eq!(host.decode(&next, from()), None);
+        assert_eq!(host.stats.out_of_order, 1);
+    }
+
+    // 同じ鍵を持ったホストとゲスト
+    fn authenticated() -> (Server, Server) {
+        let key = SessionKey([3; 32]);
+        let mut host = Server::bind(LOCALHOST).unwrap();
+        let mut guest = Server::bind(LOCALHOST).unwrap();
+        host.authenticate(key, Role::Host);
+        guest.authenticate(key, Role::Guest);
+        (host, guest)
+    }
+
+    #[test]
+    fn decode_checks_version() {
+        let (mut host, mut guest) = authenticated();
+
+        let mut buf = guest.encode(NetMessage::Ping(1), true).unwrap();
+        buf[0] = PROTOCOL_VERSION.wrapping_add(1);
+        assert_eq!(host.decode(&buf, from()), None);
+        assert_eq!(host.decode(&[], from()), None);
+        assert_eq!(host.stats.version_mismatch, 2);
+        assert_eq!(host.stats.received, 0);
+    }
+
+    #[test]
+    fn decode_rejects_malformed() {
+        let mut host = Server::bind(LOCALHOST).unwrap();
+        let mut guest = Server::bind(LOCALHOST).unwrap();
+
+        // 途中で切れたもの
+        let buf = guest.encode(NetMessage::Ping(1), false).unwrap();
+        assert_eq!(host.decode(&buf[..buf.len() - 1], from()), None);
+        // 無いメッセージの番号
+        let mut bad = buf.clone();
+        let last = bad.len() - 2;
+        bad[last] = 0xff;
+        assert_eq!(host.decode(&bad, from()), None);
+        assert_eq!(host.stats.malformed, 2);
+
+        // 鍵があればMACを確かめてから読む (長すぎるものはMACで弾かれる)
+        let (mut host, mut guest) = authenticated();
+        let buf = guest.encode(NetMessage::Ping(1), true).unwrap();
+        let (body, _) = buf.split_at(buf.len() - TAG_SIZE);
+        let mut truncated = body[..body.len() - 1].to_vec();
+        truncated.extend_from_slice(&SessionKey([3; 32]).sign(false, &truncated));
+        assert_eq!(host.decode(&truncated, from()), None);
+        assert_eq!(host.stats.malformed, 1);
+        assert_eq!(host.stats.bad_tag, 0);
+    }
+
+    #[test]
+    fn decode_rejects_old_seq() {
+        let (mut host, mut guest) = authenticated();
+
+        let first = guest.encode(NetMessage::Ping(1), true).unwrap();
+        let second = guest.encode(NetMessage::Ping(2), true).unwrap();
+        let third = guest.encode(NetMessage::Ping(3), true).unwrap();
+
+        assert_eq!(host.decode(&second, from()), Some(NetMessage::Ping(2)));
+        // 後から届いた古いもの
+        assert_eq!(host.decode(&first, from()), None);
+        // 同じものがもう一度
+        assert_eq!(host.decode(&second, from()), None);
+        assert_eq!(host.decode(&third, from()), Some(NetMessage::Ping(3)));
+        assert_eq!(host.stats.out_of_order, 2);
+        assert_eq!(host.stats.received, 2);
+    }
+
+    #[test]
+    fn decode_checks_tag() {
+        let (mut host, mut guest) = authenticated();
+
+        // MACが無い
+        let unsigned = guest.encode(NetMessage::Ping(1), false).unwrap();
+        assert_eq!(host.decode(&unsigned, from()), None);
+
+        // 中身を書き換えた
+        let mut tampered = guest.encode(NetMessage::Ping(2), true).unwrap();
+        let last = tampered.len() - TAG_SIZE - 1;
+        tampered[last] ^= 1;
+        assert_eq!(host.decode(&tampered, from()), None);
+
+        // 別の鍵
+        let mut other = Server::bind(LOCALHOST).unwrap();
+        other.authenticate(SessionKey([4; 32]), Role::Guest);
+        let forged = other.encode(NetMessage::Ping(3), true).unwrap();
+        assert_eq!(host.decode(&forged, from()), None);
+
+        // ホストが送ったものを跳ね返した
+        let reflected = host.encode(NetMessage::Ping(4), true).unwrap();
+        assert_eq!(host.decode(&reflected, from()), None);
+
+        // MACより短い
+        assert_eq!(host.decode(&[PROTOCOL_VERSION], from()), None);
+        assert_eq!(host.stats.bad_tag, 5);
+
+        let signed = guest.encode(NetMessage::Ping(5), true).unwrap();
+        assert_eq!(host.decode(&signed, from()), Some(NetMessage::Ping(5)));
+    }
+
+    // 同じ機械の上で互いにつないだホストとゲスト
+    fn pair() -> (Server, Server) {
+        let mut host = Server::bind(LOCALHOST).unwrap();
+        let mut guest = Server::bind(LOCALHOST).unwrap();
+        let user = |server: &Server| User {
+            net_port: server.port().unwrap(),
+            ..User::new("user", LOCALHOST)
+        };
+        let (host_user, guest_user) = (user(&host), user(&guest));
+        host.connect(&guest_user).unwrap();
+        guest.connect(&host_user).unwrap();
+        (host, guest)
+    }
+
+    // ローカルでもすぐには届かないことがあるので、少し待ちながら読む
+    fn recv_some(server: &mut Server) -> Vec<NetMessage> {
+        for _ in 0..100 {
+            let messages = server.recv();
+            if !messages.is_empty() {
+                return messages;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        Vec::new()
+    }
+
+    #[test]
+    fn reliable_is_acked() {
+        let (mut host, mut guest) = pair();
+
+        host.send_reliable(NetMessage::Wave { wave: 0, seed: 1 });
+        assert_eq!(host.unacked.len(), 1);
+        assert_eq!(
+            recv_some(&mut guest),
+            vec![NetMessage::Wave { wave: 0, seed: 1 }]
+        );
+
+        // `Ack` はメッセージとしては渡さない
+        for _ in 0..100 {
+            assert!(host.recv().is_empty());
+            if host.unacked.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(host.unacked.is_empty());
+    }
+
+    #[test]
+    fn reliable_inbox_orders_and_drops_resent() {
+        let mut inbox = ReliableInbox::default();
+
+        // 2が先に届いたら1を待つ
+        assert!(inbox.push(7, 2, NetMessage::Ping(2)).is_empty());
+        assert_eq!(
+            inbox.push(7, 1, NetMessage::Ping(1)),
+            vec![NetMessage::Ping(1), NetMessage::Ping(2)]
+        );
+        // 送り直されたもの
+        assert!(inbox.push(7, 1, NetMessage::Ping(1)).is_empty());
+        assert!(inbox.push(7, 2, NetMessage::Ping(2)).is_empty());
+        assert_eq!(
+            inbox.push(7, 3, NetMessage::Ping(3)),
+            vec![NetMessage::Ping(3)]
+        );
+
+        // 相手がつなぎ直したら1から
+        assert_eq!(
+            inbox.push(8, 1, NetMessage::Ping(4)),
+            vec![NetMessage::Ping(4)]
+        );
+    }
+}
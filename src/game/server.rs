@@ -1,71 +1,864 @@
-use std::net::IpAddr;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpStream};
+use std::time::Duration;
 
 use bevy::ecs::system::Resource;
+use hmac::{Hmac, Mac};
 use reqwest::{Client, Error};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use socket2::{Domain, Socket, Type};
+
+// サーバーが返事をしない場合に諦めるまでの時間
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// ホストにつながるまで待つ時間
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+// ハンドシェイクの返事を待つ時間
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+// これより長いハンドシェイクは読まない
+const MAX_HANDSHAKE_SIZE: usize = 16 * 1024;
+/// 部屋サーバーが入ってくる相手を待ってからホストに返事をするまでの時間
+pub const POLL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// ハンドシェイクの形式を変えたら上げる (1バイト目に入れる)
+pub const HANDSHAKE_VERSION: u8 = 1;
+/// このクライアントができること
+pub const CAPABILITIES: [Capability; 2] = [Capability::Relay, Capability::Spectate];
+
+/// ホストが相手の情報を待っているポート (ポートを知らせてこない古いクライアント用)
+pub const HOST_PORT: u16 = 8888;
+/// ゲーム中のデータグラムをやり取りするポート (同上)
+pub const NET_PORT: u16 = 8000;
+/// 中継するデータグラムの先頭に付ける (部屋番号 4バイト + ホストなら0、ゲストなら1)
+pub const RELAY_HEADER_SIZE: usize = 5;
+// データグラムの後ろに付けるMACの長さ (HMAC-SHA256の先頭)
+pub const TAG_SIZE: usize = 16;
+// 覚えておく相手の前のセッションの数
+const MAX_RETIRED_SESSIONS: usize = 8;
+/// 座標や状態を送る回数 (1秒あたり) の決まり
+pub const DEFAULT_TICK_RATE: u32 = 30;
+pub const MIN_TICK_RATE: u32 = 5;
+pub const MAX_TICK_RATE: u32 = 120;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Resource)]
 pub struct User {
     pub name: String,
     pub ip: IpAddr,
-    // delta_seconds()を平均した数
-    // どれくらいの間隔で座標を送ればいいか
-    pub delta_seconds: f32,
+    /// 受け取れる一番多いティックレート (ハンドシェイクで相手に伝える)
+    #[serde(default = "default_tick_rate")]
+    pub max_tick_rate: u32,
+    /// 相手の情報を待っているポート (ホストのみ)
+    #[serde(default = "host_port")]
+    pub port: u16,
+    /// ゲーム中に使うUDPのポート
+    #[serde(default = "net_port")]
+    pub net_port: u16,
+    /// ハンドシェイクや部屋の情報で相手に知らせる
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+/// クライアントが対応している機能
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// 部屋サーバーを通した中継
+    Relay,
+    /// VSの観戦者を受け付ける
+    Spectate,
+    /// 新しいクライアントが増やしたもの
+    #[serde(other)]
+    Unknown,
+}
+
+fn host_port() -> u16 {
+    HOST_PORT
+}
+
+fn net_port() -> u16 {
+    NET_PORT
+}
+
+fn default_tick_rate() -> u32 {
+    DEFAULT_TICK_RATE
+}
+
+/// 対戦ごとの鍵 (ゲーム中のデータグラムに付けるMACに使う)
+///
+/// 部屋サーバーの部屋なら部屋サーバーが作り、LANなら入る側が作ってホストに送る
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Resource)]
+pub struct SessionKey(pub [u8; 32]);
+
+impl SessionKey {
+    pub fn generate() -> SessionKey {
+        SessionKey(rand::random())
+    }
+
+    /// データグラムの後ろに付けるMAC
+    pub fn sign(&self, from_host: bool, body: &[u8]) -> [u8; TAG_SIZE] {
+        let tag = self.mac(from_host, body).finalize().into_bytes();
+        let mut truncated = [0; TAG_SIZE];
+        truncated.copy_from_slice(&tag[..TAG_SIZE]);
+        truncated
+    }
+
+    /// MACが合えば、それを除いた中身を返す
+    pub fn verify<'a>(&self, from_host: bool, buf: &'a [u8]) -> Option<&'a [u8]> {
+        let (body, tag) = buf.split_at(buf.len().checked_sub(TAG_SIZE)?);
+        self.mac(from_host, body)
+            .verify_truncated_left(tag)
+            .ok()
+            .map(|_| body)
+    }
+
+    // 送る側がホストかどうかも入れて、片方のデータグラムをもう片方のものにできないようにする
+    fn mac(&self, from_host: bool, body: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC accepts any key length");
+        mac.update(&[u8::from(from_host)]);
+        mac.update(body);
+        mac
+    }
+}
+
+/// 相手から受け取ったデータグラムのセッションと通し番号
+///
+/// 相手がつなぎ直して番号が初めからになっても、新しいセッションなら受け取る
+#[derive(Default, Debug)]
+pub struct SeqWindow {
+    session: Option<u32>,
+    last_seq: Option<u32>,
+    // 前に使われていたセッション (送り直されても受け取らない)
+    retired: Vec<u32>,
+}
+
+impl SeqWindow {
+    /// 最後に受け取ったセッション
+    pub fn session(&self) -> Option<u32> {
+        self.session
+    }
+
+    /// 前に受け取ったものより新しければ覚えて true
+    pub fn accept(&mut self, session: u32, seq: u32) -> bool {
+        if self.session != Some(session) {
+            if self.retired.contains(&session) {
+                return false;
+            }
+            if let Some(old) = self.session.replace(session) {
+                if self.retired.len() >= MAX_RETIRED_SESSIONS {
+                    self.retired.remove(0);
+                }
+                self.retired.push(old);
+            }
+            self.last_seq = None;
+        }
+        if self.last_seq.is_some_and(|last| seq <= last) {
+            return false;
+        }
+        self.last_seq = Some(seq);
+        true
+    }
+}
+
+/// 部屋を作った人だけが持つ合言葉 (結果を送るときや部屋を閉じるときに見せる)
+///
+/// 対戦の鍵はゲストも持っているので、ホストかどうかはこれで確かめる (作るのは部屋サーバーだけ)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct HostToken(pub [u8; 16]);
+
+/// 部屋で遊ぶゲーム
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OnlineMode {
+    #[default]
+    VS,
+    Coop,
+}
+
+/// VSで使える攻撃
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weapon {
+    Normal,
+    Power,
+    Shotgun,
+    Rebound,
+}
+
+/// VSのルール (部屋を作った側が決めて、入った側はそれに従う)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Resource)]
+#[serde(default)]
+pub struct Rules {
+    /// 最初のHP
+    pub hp: isize,
+    /// 自機の速さ
+    pub speed: f32,
+    /// 切り替えられる攻撃 (最初のものから始める)
+    pub weapons: Vec<Weapon>,
+    /// 1試合の制限時間 (秒)。時間切れならHPが多い方の勝ち
+    pub time_limit: Option<u32>,
+    /// 何本勝負か
+    pub best_of: u32,
+    /// 座標や状態を送る回数 (1秒あたり)。入る側が受け取れる数までに下げる
+    pub tick_rate: u32,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            hp: 50,
+            speed: 300.,
+            weapons: vec![
+                Weapon::Normal,
+                Weapon::Power,
+                Weapon::Rebound,
+                Weapon::Shotgun,
+            ],
+            time_limit: None,
+            best_of: 1,
+            tick_rate: DEFAULT_TICK_RATE,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Resource, Clone)]
 pub struct RoomRequest {
     pub room_id: u32,
     pub user: User,
+    /// ゲーム中のデータグラムの形式 (ホストに伝える)
+    #[serde(default)]
+    pub version: u8,
+    #[serde(default)]
+    pub mode: OnlineMode,
+    #[serde(default)]
+    pub rules: Rules,
+    /// 部屋を作ったときにもらった合言葉 (部屋を閉じるときに見せる)
+    #[serde(default)]
+    pub token: Option<HostToken>,
 }
 
 impl RoomRequest {
-    pub fn new(room_id: u32, user: User) -> RoomRequest {
-        RoomRequest { room_id, user }
+    pub fn new(room_id: u32, user: User, version: u8) -> RoomRequest {
+        RoomRequest {
+            room_id,
+            user,
+            version,
+            mode: OnlineMode::default(),
+            rules: Rules::default(),
+            token: None,
+        }
     }
 }
 
 impl User {
-    pub fn new(name: &str, ip: IpAddr, delta_seconds: f32) -> User {
+    pub fn new(name: &str, ip: IpAddr) -> User {
         User {
             name: name.to_string(),
             ip,
-            delta_seconds,
+            max_tick_rate: MAX_TICK_RATE,
+            port: HOST_PORT,
+            net_port: NET_PORT,
+            capabilities: CAPABILITIES.to_vec(),
         }
     }
 }
 
+/// 部屋の一覧に出す情報
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomInfo {
+    pub room_id: u32,
+    pub host: String,
+    pub mode: OnlineMode,
+    #[serde(default)]
+    pub rules: Rules,
+    // 部屋を作った時刻 (UNIX時間の秒)
+    pub created: u64,
+}
+
+/// 観戦できる対戦 (VSで相手が入った部屋)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MatchInfo {
+    pub room_id: u32,
+    // 観戦者はホストにつなぐ
+    pub host: User,
+    pub guest: String,
+    pub rules: Rules,
+    // 相手が入った時刻 (UNIX時間の秒)
+    pub started: u64,
+}
+
+/// 終わったVSの対戦の記録
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MatchRecord {
+    pub host: String,
+    pub guest: String,
+    /// 勝った方の名前 (引き分けならNone)
+    pub winner: Option<String>,
+    /// 対戦にかかった時間 (秒)
+    pub duration: u64,
+    /// 最後の試合が終わったときのHP
+    pub host_hp: isize,
+    pub guest_hp: isize,
+    /// 終わった時刻 (UNIX時間の秒、部屋サーバーが決める)
+    #[serde(default)]
+    pub finished: u64,
+}
+
+/// ホストが部屋サーバーに送る対戦の結果
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MatchReport {
+    pub room_id: u32,
+    pub token: HostToken,
+    /// 最後の試合の番号 (同じ対戦を2回記録しないように)
+    pub round: u32,
+    pub record: MatchRecord,
+}
+
+/// プレイヤーのレーティングと成績
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rating {
+    pub name: String,
+    pub rating: f64,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ResultResponse {
-    Ok { message: String, user: Option<User> },
+    Ok {
+        message: String,
+        user: Option<User>,
+        // 入った部屋のゲーム
+        #[serde(default)]
+        mode: OnlineMode,
+        // 入った部屋のルール
+        #[serde(default)]
+        rules: Rules,
+        // 作った部屋か入った部屋の鍵
+        #[serde(default)]
+        key: Option<SessionKey>,
+        // 作った部屋の合言葉 (ホストにだけ返す)
+        #[serde(default)]
+        token: Option<HostToken>,
+    },
     Err(String),
 }
 
 // 部屋の作成の申請
 #[actix_web::main]
-pub async fn room_create(room_request: RoomRequest) -> Result<ResultResponse, Error> {
-    let res = Client::new()
-        .post("http://192.168.11.6:9999/create")
+pub async fn room_create(server: &str, room_request: RoomRequest) -> Result<ResultResponse, Error> {
+    let res = Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?
+        .post(format!("{}/create", server))
         .json(&room_request)
         .send()
         .await?;
     let json = res.json::<ResultResponse>().await?;
-    println!("{:?}", json);
 
     Ok(json)
 }
 
 // 部屋に入る
 #[actix_web::main]
-pub async fn room_enter(room_request: RoomRequest) -> Result<ResultResponse, Error> {
-    let res = Client::new()
-        .post("http://192.168.11.6:9999/enter")
+pub async fn room_enter(server: &str, room_request: RoomRequest) -> Result<ResultResponse, Error> {
+    let res = Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?
+        .post(format!("{}/enter", server))
+        .json(&room_request)
+        .send()
+        .await?;
+    let json = res.json::<ResultResponse>().await?;
+
+    Ok(json)
+}
+
+// 入ってきた相手を待つ (部屋サーバーは相手が来るか `POLL_TIMEOUT` が過ぎるまで返事をしない)
+#[actix_web::main]
+pub async fn room_wait(server: &str, room_request: RoomRequest) -> Result<WaitResponse, Error> {
+    let res = Client::builder()
+        .timeout(POLL_TIMEOUT + REQUEST_TIMEOUT)
+        .build()?
+        .post(format!("{}/wait", server))
+        .json(&room_request)
+        .send()
+        .await?;
+    let json = res.json::<WaitResponse>().await?;
+
+    Ok(json)
+}
+
+// 入ってきた相手に返事をする
+#[actix_web::main]
+pub async fn room_answer(server: &str, answer: Answer) -> Result<ResultResponse, Error> {
+    let res = Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?
+        .post(format!("{}/answer", server))
+        .json(&answer)
+        .send()
+        .await?;
+    let json = res.json::<ResultResponse>().await?;
+
+    Ok(json)
+}
+
+// 部屋を閉じる
+#[actix_web::main]
+pub async fn room_leave(server: &str, room_request: RoomRequest) -> Result<ResultResponse, Error> {
+    let res = Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?
+        .post(format!("{}/leave", server))
         .json(&room_request)
         .send()
         .await?;
     let json = res.json::<ResultResponse>().await?;
 
-    println!("{:?}", json);
+    Ok(json)
+}
+
+// 入れる部屋の一覧
+#[actix_web::main]
+pub async fn room_list(server: &str) -> Result<Vec<RoomInfo>, Error> {
+    let res = Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?
+        .get(format!("{}/rooms", server))
+        .send()
+        .await?;
+    let json = res.json::<Vec<RoomInfo>>().await?;
+
+    Ok(json)
+}
+
+// 観戦できる対戦の一覧
+#[actix_web::main]
+pub async fn match_list(server: &str) -> Result<Vec<MatchInfo>, Error> {
+    let res = Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?
+        .get(format!("{}/matches", server))
+        .send()
+        .await?;
+    let json = res.json::<Vec<MatchInfo>>().await?;
 
     Ok(json)
 }
+
+// 対戦の結果を記録してもらう
+#[actix_web::main]
+pub async fn match_report(server: &str, report: MatchReport) -> Result<ResultResponse, Error> {
+    let res = Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?
+        .post(format!("{}/report", server))
+        .json(&report)
+        .send()
+        .await?;
+    let json = res.json::<ResultResponse>().await?;
+
+    Ok(json)
+}
+
+// プレイヤーのレーティング
+#[actix_web::main]
+pub async fn player_rating(server: &str, name: &str) -> Result<Rating, Error> {
+    let res = Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?
+        .get(format!("{}/players/{}", server, path_segment(name)))
+        .send()
+        .await?;
+    let json = res.json::<Rating>().await?;
+
+    Ok(json)
+}
+
+// URLのパスに入れられるようにする
+fn path_segment(str: &str) -> String {
+    str.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// ハンドシェイクで送る自分の情報
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    /// ゲーム中のデータグラムの形式
+    pub version: u8,
+    pub user: User,
+    /// 入る側が知っている対戦の鍵 (ホストは送らない)
+    pub key: Option<SessionKey>,
+    /// ホストが決めたティックレート (入る側は送らない)
+    #[serde(default)]
+    pub tick_rate: Option<u32>,
+}
+
+/// 部屋に入るときのホストとのやり取り
+///
+/// 入る側の `Hello`、ホストの `Hello`、入る側の `Ack` の順に送る。
+/// 相手の `Hello` を受け入れられなければ代わりに `Reject` を送る
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Handshake {
+    Hello(Hello),
+    Ack,
+    Reject(String),
+}
+
+/// ホストが部屋サーバーに聞いた、部屋に入ってきた相手
+#[derive(Serialize, Deserialize, Debug)]
+pub enum WaitResponse {
+    Guest(Hello),
+    /// まだ誰も入ってこない (もう一度聞く)
+    Empty,
+    Err(String),
+}
+
+/// ホストが部屋サーバーを通して入ってきた相手に送る返事 (`Hello` か `Reject`)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Answer {
+    pub room_id: u32,
+    pub token: HostToken,
+    pub reply: Handshake,
+}
+
+/// 形式 (1バイト) と長さ (4バイト) を付けて送る
+pub fn write_handshake(stream: &mut impl Write, message: &Handshake) -> io::Result<()> {
+    let json = serde_json::to_vec(message)?;
+    if json.len() > MAX_HANDSHAKE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "handshake is too long",
+        ));
+    }
+
+    let mut buf = vec![HANDSHAKE_VERSION];
+    buf.extend_from_slice(&(json.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&json);
+    stream.write_all(&buf)
+}
+
+/// 分かれて届いても長さの分だけ読む
+pub fn read_handshake(stream: &mut impl Read) -> io::Result<Handshake> {
+    let mut header = [0; 5];
+    stream.read_exact(&mut header)?;
+    if header[0] != HANDSHAKE_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "handshake version mismatch (expected {}, got {})",
+                HANDSHAKE_VERSION, header[0]
+            ),
+        ));
+    }
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_HANDSHAKE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "handshake is too long",
+        ));
+    }
+
+    let mut json = vec![0; len];
+    stream.read_exact(&mut json)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// 接続画面に出すバージョン違いの説明
+pub fn version_mismatch(host: u8, guest: u8) -> String {
+    format!(
+        "version mismatch: host uses protocol {}, guest uses {}",
+        host, guest
+    )
+}
+
+/// 部屋のルールと2人が受け取れる数のうち一番少ないもの
+/// どちらかが `MIN_TICK_RATE` も受け取れないときは対戦できない
+pub fn negotiate_tick_rate(rules: &Rules, host: &User, guest: &User) -> Result<u32, String> {
+    let max_tick_rate = host.max_tick_rate.min(guest.max_tick_rate);
+    if max_tick_rate < MIN_TICK_RATE {
+        return Err(format!(
+            "tick rate {} is below the minimum {}",
+            max_tick_rate, MIN_TICK_RATE
+        ));
+    }
+    Ok(rules
+        .tick_rate
+        .min(max_tick_rate)
+        .clamp(MIN_TICK_RATE, MAX_TICK_RATE))
+}
+
+/// 入る側の `Hello` へのホストの返事を確かめる
+/// (バージョンが同じで、ティックレートが入る側の受け取れる数まで)
+pub fn check_reply(hello: &Hello, reply: &Hello) -> Result<(), String> {
+    if reply.version != hello.version {
+        Err(version_mismatch(reply.version, hello.version))
+    } else if !reply
+        .tick_rate
+        .is_some_and(|rate| (MIN_TICK_RATE..=hello.user.max_tick_rate).contains(&rate))
+    {
+        Err(format!("unsupported tick rate: {:?}", reply.tick_rate))
+    } else {
+        Ok(())
+    }
+}
+
+/// ホストに入る側の情報と対戦の鍵を送り、ホストの情報を受け取る
+pub fn notify_host(host: &User, hello: Hello) -> io::Result<Hello> {
+    let address = SocketAddr::new(host.ip, host.port);
+    let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    write_handshake(&mut stream, &Handshake::Hello(hello.clone()))?;
+
+    match read_handshake(&mut stream)? {
+        Handshake::Hello(reply) => match check_reply(&hello, &reply) {
+            Ok(()) => {
+                write_handshake(&mut stream, &Handshake::Ack)?;
+                Ok(reply)
+            }
+            Err(reason) => {
+                // 断るのが届かなくてもホストは待ち続けるだけ
+                let _ = write_handshake(&mut stream, &Handshake::Reject(reason.clone()));
+                Err(io::Error::other(reason))
+            }
+        },
+        Handshake::Reject(reason) => Err(io::Error::other(reason)),
+        Handshake::Ack => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected handshake",
+        )),
+    }
+}
+
+/// `addr` で開ける。IPv6の未指定アドレス (`[::]`) ならIPv4からも届くようにする
+pub fn bind_socket(addr: SocketAddr, ty: Type) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+    if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+    // 再起動してすぐ同じポートで待ち受けられるように
+    if cfg!(unix) && ty == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// IPv4とIPv6の両方で開けてみて、だめなら `ip` の種類だけで開ける (ポートは空いているもの)
+pub fn bind_dual_stack(ip: IpAddr, ty: Type) -> io::Result<Socket> {
+    bind_socket(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0), ty)
+        .or_else(|_| bind_socket(SocketAddr::new(ip, 0), ty))
+}
+
+pub fn relay_header(room_id: u32, host: bool) -> [u8; RELAY_HEADER_SIZE] {
+    let [a, b, c, d] = room_id.to_be_bytes();
+    [a, b, c, d, u8::from(!host)]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+
+    use super::*;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    // 1回に1バイトずつしか読めない (TCPで分かれて届いたときの代わり)
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    fn encoded(message: &Handshake) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_handshake(&mut buf, message).unwrap();
+        buf
+    }
+
+    fn hello(version: u8) -> Hello {
+        Hello {
+            version,
+            user: User::new("guest", LOCALHOST),
+            key: Some(SessionKey([7; 32])),
+            tick_rate: None,
+        }
+    }
+
+    #[test]
+    fn handshake_round_trip_in_pieces() {
+        let buf = encoded(&Handshake::Hello(hello(3)));
+        assert_eq!(buf[0], HANDSHAKE_VERSION);
+
+        let Handshake::Hello(read) = read_handshake(&mut Trickle(Cursor::new(buf))).unwrap() else {
+            panic!("expected hello");
+        };
+        assert_eq!(read.version, 3);
+        assert_eq!(read.user.name, "guest");
+        assert_eq!(read.key, Some(SessionKey([7; 32])));
+
+        // 続けて送ったものは1つずつ読める
+        let mut buf = encoded(&Handshake::Ack);
+        buf.extend(encoded(&Handshake::Reject("full".to_string())));
+        let mut stream = Trickle(Cursor::new(buf));
+        assert!(matches!(read_handshake(&mut stream), Ok(Handshake::Ack)));
+        assert!(matches!(
+            read_handshake(&mut stream),
+            Ok(Handshake::Reject(reason)) if reason == "full"
+        ));
+    }
+
+    #[test]
+    fn handshake_size_limit() {
+        // JSONがちょうど上限の長さになる `Reject`
+        let overhead = serde_json::to_vec(&Handshake::Reject(String::new()))
+            .unwrap()
+            .len();
+        let largest = Handshake::Reject("a".repeat(MAX_HANDSHAKE_SIZE - overhead));
+        let buf = encoded(&largest);
+        assert_eq!(buf.len(), 5 + MAX_HANDSHAKE_SIZE);
+        assert!(read_handshake(&mut Cursor::new(buf)).is_ok());
+
+        let too_long = Handshake::Reject("a".repeat(MAX_HANDSHAKE_SIZE - overhead + 1));
+        let e = write_handshake(&mut Vec::new(), &too_long).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        // 長さが上限を超えていれば中身を読まずに断る
+        let mut buf = vec![HANDSHAKE_VERSION];
+        buf.extend(((MAX_HANDSHAKE_SIZE + 1) as u32).to_be_bytes());
+        let e = read_handshake(&mut Cursor::new(buf)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn handshake_bad_header() {
+        let buf = encoded(&Handshake::Ack);
+
+        // 形式が違う
+        let mut wrong_version = buf.clone();
+        wrong_version[0] = HANDSHAKE_VERSION + 1;
+        let e = read_handshake(&mut Cursor::new(wrong_version)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // 長さが中身より長い
+        let mut too_long = buf.clone();
+        too_long[4] += 1;
+        let e = read_handshake(&mut Cursor::new(too_long)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        // 長さが中身より短い (JSONが途中で切れる)
+        let mut too_short = buf.clone();
+        too_short[4] -= 1;
+        let e = read_handshake(&mut Cursor::new(too_short)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        // JSONではない
+        let mut garbage = buf[..5].to_vec();
+        garbage.extend(vec![b'x'; buf.len() - 5]);
+        let e = read_handshake(&mut Cursor::new(garbage)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // ヘッダーの途中で切れた
+        let e = read_handshake(&mut Cursor::new(buf[..3].to_vec())).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn tick_rate_is_the_lowest() {
+        let user = |max_tick_rate| User {
+            max_tick_rate,
+            ..User::new("user", LOCALHOST)
+        };
+        let rules = |tick_rate| Rules {
+            tick_rate,
+            ..Rules::default()
+        };
+
+        // 部屋のルールと2人のうち一番小さいもの
+        assert_eq!(
+            negotiate_tick_rate(&rules(60), &user(120), &user(20)),
+            Ok(20)
+        );
+        assert_eq!(
+            negotiate_tick_rate(&rules(60), &user(45), &user(120)),
+            Ok(45)
+        );
+        assert_eq!(
+            negotiate_tick_rate(&rules(30), &user(60), &user(90)),
+            Ok(30)
+        );
+
+        // 範囲の外は範囲に収める
+        assert_eq!(
+            negotiate_tick_rate(&rules(1000), &user(500), &user(600)),
+            Ok(MAX_TICK_RATE)
+        );
+        assert_eq!(
+            negotiate_tick_rate(&rules(0), &user(60), &user(60)),
+            Ok(MIN_TICK_RATE)
+        );
+    }
+
+    #[test]
+    fn tick_rate_never_exceeds_either_max() {
+        let user = |max_tick_rate| User {
+            max_tick_rate,
+            ..User::new("user", LOCALHOST)
+        };
+        let rules = Rules {
+            tick_rate: 0,
+            ..Rules::default()
+        };
+
+        // 最小まで受け取れるならその数
+        assert_eq!(
+            negotiate_tick_rate(&rules, &user(60), &user(MIN_TICK_RATE)),
+            Ok(MIN_TICK_RATE)
+        );
+        // 最小より少ない人がいれば引き上げずに断る
+        assert!(negotiate_tick_rate(&rules, &user(60), &user(MIN_TICK_RATE - 1)).is_err());
+        assert!(negotiate_tick_rate(&rules, &user(0), &user(120)).is_err());
+    }
+
+    #[test]
+    fn notify_host_rejects_other_version() {
+        let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        let host = User {
+            port: listener.local_addr().unwrap().port(),
+            ..User::new("host", LOCALHOST)
+        };
+
+        // 違うバージョンで返事をするホスト
+        let fake_host = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let Handshake::Hello(guest) = read_handshake(&mut stream).unwrap() else {
+                panic!("expected hello");
+            };
+            let reply = Hello {
+                tick_rate: Some(DEFAULT_TICK_RATE),
+                ..hello(guest.version + 1)
+            };
+            write_handshake(&mut stream, &Handshake::Hello(reply)).unwrap();
+            read_handshake(&mut stream).unwrap()
+        });
+
+        let e = notify_host(&host, hello(3)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert_eq!(e.to_string(), version_mismatch(4, 3));
+        assert!(matches!(
+            fake_host.join().unwrap(),
+            Handshake::Reject(reason) if reason == version_mismatch(4, 3)
+        ));
+    }
+}
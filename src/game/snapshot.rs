@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+// 古いものから捨てる
+const SNAPSHOT_CAPACITY: usize = 32;
+// パケットが途切れたときに先読みする最大時間
+const MAX_EXTRAPOLATION: f64 = 0.25;
+// 時計のずれの推定値を下げる速さ
+const OFFSET_SMOOTHING: f64 = 0.02;
+
+#[derive(Clone, Copy)]
+struct Snapshot {
+    time: f64,
+    position: Vec2,
+}
+
+/// 相手から届いた座標の履歴
+///
+/// 少し過去の時刻を補間して描画し、届かなくなったら短い間だけ外挿する
+#[derive(Component, Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<Snapshot>,
+    // 相手の時計 - こちらの時計 (遅延が一番小さかったときの値に寄せる)
+    offset: Option<f64>,
+}
+
+impl SnapshotBuffer {
+    pub fn push(&mut self, remote_time: f64, local_time: f64, position: Vec2) {
+        if self
+            .snapshots
+            .back()
+            .is_some_and(|last| last.time >= remote_time)
+        {
+            return;
+        }
+
+        let offset = remote_time - local_time;
+        self.offset = Some(match self.offset {
+            Some(current) if offset < current => current + (offset - current) * OFFSET_SMOOTHING,
+            _ => offset,
+        });
+
+        self.snapshots.push_back(Snapshot {
+            time: remote_time,
+            position,
+        });
+        if self.snapshots.len() > SNAPSHOT_CAPACITY {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// `delay` 秒前の相手の座標
+    pub fn sample(&mut self, local_time: f64, delay: f64) -> Option<Vec2> {
+        let offset = self.offset?;
+        let render_time = local_time + offset - delay;
+
+        // 補間に使わない古いものを捨てる
+        while self.snapshots.len() > 2 && self.snapshots[1].time <= render_time {
+            self.snapshots.pop_front();
+        }
+
+        let first = *self.snapshots.front()?;
+        let last = *self.snapshots.back()?;
+
+        if render_time <= first.time {
+            return Some(first.position);
+        }
+        if render_time <= last.time {
+            let next = self.snapshots.iter().position(|s| s.time >= render_time)?;
+            let (a, b) = (self.snapshots[next - 1], self.snapshots[next]);
+            let t = (render_time - a.time) / (b.time - a.time);
+            return Some(a.position.lerp(b.position, t as f32));
+        }
+
+        // 外挿
+        let len = self.snapshots.len();
+        if len < 2 {
+            return Some(last.position);
+        }
+        let prev = self.snapshots[len - 2];
+        let velocity = (last.position - prev.position) / (last.time - prev.time) as f32;
+        let ahead = (render_time - last.time).min(MAX_EXTRAPOLATION);
+
+        Some(last.position + velocity * ahead as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 相手の時計はこちらより10秒遅れている
+    const LOCAL: f64 = 10.;
+
+    fn buffer(snapshots: &[(f64, Vec2)]) -> SnapshotBuffer {
+        let mut buffer = SnapshotBuffer::default();
+        for &(time, position) in snapshots {
+            buffer.push(time, LOCAL + time, position);
+        }
+        buffer
+    }
+
+    fn assert_near(actual: Option<Vec2>, expected: Vec2) {
+        let actual = actual.unwrap();
+        assert!(
+            actual.distance(expected) < 1e-4,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn interpolates_between_snapshots() {
+        let mut buffer = buffer(&[(0., Vec2::ZERO), (1., Vec2::new(10., 20.))]);
+
+        assert_near(buffer.sample(LOCAL + 0.75, 0.5), Vec2::new(2.5, 5.));
+        assert_near(buffer.sample(LOCAL + 1., 0.5), Vec2::new(5., 10.));
+        assert_near(buffer.sample(LOCAL + 1.5, 0.5), Vec2::new(10., 20.));
+    }
+
+    #[test]
+    fn clamps_at_both_ends() {
+        let mut buffer = buffer(&[(0., Vec2::ZERO), (1., Vec2::new(10., 0.))]);
+
+        // 最初のものより前
+        assert_near(buffer.sample(LOCAL - 5., 0.), Vec2::ZERO);
+        // 最後のものより後は速さのまま先読みするが、`MAX_EXTRAPOLATION` まで
+        assert_near(buffer.sample(LOCAL + 1.1, 0.), Vec2::new(11., 0.));
+        assert_near(
+            buffer.sample(LOCAL + 5., 0.),
+            Vec2::new(10. + 10. * MAX_EXTRAPOLATION as f32, 0.),
+        );
+
+        // 1つしかなければその場所
+        let mut single = self::buffer(&[(0., Vec2::new(3., 4.))]);
+        assert_near(single.sample(LOCAL + 5., 0.), Vec2::new(3., 4.));
+
+        // まだ何も届いていない
+        assert_eq!(SnapshotBuffer::default().sample(LOCAL, 0.), None);
+    }
+
+    #[test]
+    fn ignores_out_of_order() {
+        let mut buffer = buffer(&[(0., Vec2::ZERO), (1., Vec2::new(10., 0.))]);
+
+        // 後から届いた古いものと、同じ時刻のもの
+        buffer.push(0.5, LOCAL + 1.2, Vec2::new(100., 0.));
+        buffer.push(1., LOCAL + 1.2, Vec2::new(100., 0.));
+        assert_eq!(buffer.snapshots.len(), 2);
+        assert_near(buffer.sample(LOCAL + 0.5, 0.), Vec2::new(5., 0.));
+    }
+
+    #[test]
+    fn trims_old_snapshots() {
+        let snapshots: Vec<(f64, Vec2)> = (0..SNAPSHOT_CAPACITY + 8)
+            .map(|i| (i as f64, Vec2::new(i as f32, 0.)))
+            .collect();
+        let mut buffer = buffer(&snapshots);
+        assert_eq!(buffer.snapshots.len(), SNAPSHOT_CAPACITY);
+        assert_eq!(buffer.snapshots[0].time, 8.);
+
+        // 描画する時刻より前のものは、補間に使う1つだけ残す
+        assert_near(buffer.sample(LOCAL + 20.5, 0.), Vec2::new(20.5, 0.));
+        assert_eq!(buffer.snapshots[0].time, 20.);
+        assert_near(buffer.sample(LOCAL + 100., 0.), Vec2::new(39.25, 0.));
+        assert_eq!(buffer.snapshots.len(), 2);
+    }
+
+    #[test]
+    fn offset_follows_the_fastest_packet() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(0., LOCAL, Vec2::ZERO);
+        // 遅れて届いたものでは少しずつしか動かない
+        buffer.push(1., LOCAL + 2., Vec2::ZERO);
+        let offset = buffer.offset.unwrap();
+        assert!((offset - (-LOCAL - OFFSET_SMOOTHING)).abs() < 1e-9);
+        // 早く届いたものにはすぐ合わせる
+        buffer.push(2., LOCAL + 1.5, Vec2::ZERO);
+        assert_eq!(buffer.offset, Some(0.5 - LOCAL));
+    }
+}
@@ -1,9 +1,11 @@
 use bevy::ui::Val;
 
+mod coop_play;
 mod single_play;
 mod tow_player;
 mod vs_player;
 
+pub use coop_play::*;
 pub use single_play::*;
 pub use tow_player::*;
 pub use vs_player::*;
@@ -0,0 +1,418 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use super::TEXT_PADDING;
+use crate::entity::*;
+use crate::game::*;
+use crate::method_impl;
+use crate::setting::Setting;
+use crate::{FontResource, MainState, TextureResource};
+
+const HOST_POSITION: Vec2 = Vec2::new(-100., -450.);
+const GUEST_POSITION: Vec2 = Vec2::new(100., -450.);
+
+const INITIAL_COOP_PLAYER_HP: isize = 30;
+
+const TEXT_COLOR: Color = Color::WHITE;
+
+pub struct CoopPlay;
+
+impl Plugin for CoopPlay {
+    fn build(&self, app: &mut App) {
+        app.add_event::<NetMessage>()
+            .add_systems(
+                OnEnter(GameMode::Coop),
+                (net_connect.run_if(resource_exists::<Server>()), coop_setup),
+            )
+            .add_systems(OnExit(GameMode::Coop), coop_exit)
+            .add_systems(
+                PreUpdate,
+                (net_recv, net_tick)
+                    .run_if(resource_exists::<Server>())
+                    .run_if(in_state(GameMode::Coop)),
+            )
+            .add_systems(
+                Update,
+                (
+                    update_info,
+                    move_player::<CoopPlayer>,
+                    move_partner,
+                    partner_attack,
+                    move_player_attack::<PartnerAttack>,
+                    position_send.run_if(on_net_tick),
+                    fire_send,
+                    status_send,
+                    wave_send,
+                    hit_send,
+                    enemy_fire_send,
+                    net_event,
+                    enemy_fire_recv,
+                    connection_check,
+                    relay_fallback,
+                )
+                    .run_if(resource_exists::<Server>())
+                    .run_if(in_state(GameMode::Coop)),
+            )
+            .add_systems(Update, net_closing.run_if(resource_exists::<Closing>()))
+            .add_plugins(
+                GamePlayPlugin::<CoopPlayer, CoopPlayerAttack, CoopPlayerEvent> {
+                    setting: PluginSetting {
+                        enemy_create_timer: Duration::from_secs_f32(3.),
+                        enemy_attack_timer: Duration::from_secs_f32(1.),
+                        in_state: GameMode::Coop,
+                        ..default()
+                    },
+                },
+            );
+    }
+}
+
+#[derive(Event, Clone)]
+pub struct CoopPlayerEvent {
+    attack: AttackType,
+}
+
+#[derive(Component, Clone, PartialEq)]
+pub struct CoopPlayerAttack(Attack);
+
+#[derive(Component, Default, Clone)]
+pub struct CoopPlayer(Player);
+
+// もう一人のプレイヤー (表示だけ)
+#[derive(Component)]
+struct Partner;
+
+#[derive(Component, Clone, PartialEq)]
+struct PartnerAttack(Attack);
+
+#[derive(Resource)]
+struct PartnerStatus {
+    hp: isize,
+    kill: usize,
+}
+
+#[derive(Component)]
+enum BoardSection {
+    Hp,
+    Kill,
+    PartnerHp,
+    PartnerKill,
+}
+
+fn coop_setup(
+    mut commands: Commands,
+    texture: Res<TextureResource>,
+    font: Res<FontResource>,
+    partner: Res<User>,
+    role: Res<Role>,
+) {
+    commands.insert_resource(PartnerStatus {
+        hp: INITIAL_COOP_PLAYER_HP,
+        kill: INITIAL_KILLCOUNT,
+    });
+
+    let text_style = TextStyle {
+        font: font.0.clone(),
+        font_size: 50.,
+        color: TEXT_COLOR,
+    };
+    let name_style = TextStyle {
+        font: font.0.clone(),
+        font_size: 60.,
+        color: TEXT_COLOR,
+    };
+
+    let board = [
+        (
+            "You".to_string(),
+            BoardSection::Hp,
+            BoardSection::Kill,
+            Style {
+                flex_direction: FlexDirection::Column,
+                position_type: PositionType::Absolute,
+                top: TEXT_PADDING,
+                left: TEXT_PADDING,
+                ..default()
+            },
+        ),
+        (
+            partner.name.clone(),
+            BoardSection::PartnerHp,
+            BoardSection::PartnerKill,
+            Style {
+                flex_direction: FlexDirection::Column,
+                position_type: PositionType::Absolute,
+                top: TEXT_PADDING,
+                right: TEXT_PADDING,
+                ..default()
+            },
+        ),
+    ];
+    for (name, hp, kill, style) in board {
+        commands
+            .spawn((NodeBundle { style, ..default() }, PlayerInfoScreen))
+            .with_children(|parent| {
+                parent.spawn(TextBundle::from_section(name, name_style.clone()));
+                parent.spawn((
+                    TextBundle::from_sections([
+                        TextSection::new("HP", text_style.clone()),
+                        TextSection::new(INITIAL_COOP_PLAYER_HP.to_string(), text_style.clone()),
+                    ]),
+                    hp,
+                ));
+                parent.spawn((
+                    TextBundle::from_sections([
+                        TextSection::new("KILL", text_style.clone()),
+                        TextSection::new(INITIAL_KILLCOUNT.to_string(), text_style.clone()),
+                    ]),
+                    kill,
+                ));
+            });
+    }
+
+    let (my_position, partner_position) = match *role {
+        Role::Host => (HOST_POSITION, GUEST_POSITION),
+        Role::Guest => (GUEST_POSITION, HOST_POSITION),
+    };
+
+    commands.spawn(PlayerBundle::new(
+        CoopPlayer(Player {
+            hp: INITIAL_COOP_PLAYER_HP,
+            ..default()
+        }),
+        texture.player.clone(),
+        my_position,
+    ));
+    commands.spawn((
+        SpriteBundle {
+            transform: Transform {
+                translation: partner_position.extend(0.),
+                scale: PLAYER_SIZE.extend(0.0),
+                ..default()
+            },
+            texture: texture.player.clone(),
+            sprite: Sprite {
+                custom_size: Some(Vec2::new(2., 2.)),
+                ..default()
+            },
+            ..default()
+        },
+        Partner,
+        SnapshotBuffer::default(),
+    ));
+}
+
+type PartnerType = Or<(With<Partner>, With<PartnerAttack>)>;
+
+// 相手にゲームの終わりを伝えて片付ける
+// 終わりが届くまでソケットは `Closing` として少し残る
+fn coop_exit(
+    mut commands: Commands,
+    server: Option<ResMut<Server>>,
+    partner_query: Query<Entity, PartnerType>,
+) {
+    if let Some(mut server) = server {
+        server.send_reliable(NetMessage::GameOver);
+        close_server(&mut commands);
+    }
+
+    for entity in &partner_query {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    commands.remove_resource::<Relay>();
+    commands.remove_resource::<SessionKey>();
+    commands.remove_resource::<Rules>();
+    commands.remove_resource::<Role>();
+    commands.remove_resource::<PartnerStatus>();
+}
+
+fn update_info(
+    player_query: Query<&CoopPlayer>,
+    partner: Res<PartnerStatus>,
+    mut text_query: Query<(&mut Text, &BoardSection)>,
+    mut update_info_event: EventReader<UpdateInfo>,
+) {
+    if !update_info_event.is_empty() {
+        update_info_event.clear();
+
+        let player = player_query.single();
+        for (mut text, section) in &mut text_query {
+            text.sections[1].value = match section {
+                BoardSection::Hp => player.hp().to_string(),
+                BoardSection::Kill => player.get_kill().to_string(),
+                BoardSection::PartnerHp => partner.hp.to_string(),
+                BoardSection::PartnerKill => partner.kill.to_string(),
+            }
+        }
+    }
+}
+
+// もう一人を動かす
+fn move_partner(
+    mut query: Query<(&mut Transform, &mut SnapshotBuffer), With<Partner>>,
+    mut event: EventReader<NetMessage>,
+    time: Res<Time>,
+    setting: Res<Setting>,
+) {
+    let (mut transform, mut snapshots) = query.single_mut();
+    let now = time.elapsed_seconds_f64();
+
+    for message in event.read() {
+        if let NetMessage::Position { x, y, time: sent } = *message {
+            snapshots.push(sent, now, Vec2::new(x, y));
+        }
+    }
+    if let Some(position) = snapshots.sample(now, setting.interpolation_delay) {
+        transform.translation = position.extend(0.0);
+    }
+}
+
+// もう一人の攻撃 (敵には当たらない)
+// 弾1つにつき `Fire` が1つ届くので、広がる攻撃でもそのまま1つ出す
+fn partner_attack(
+    mut commands: Commands,
+    texture: Res<TextureResource>,
+    mut event: EventReader<NetMessage>,
+) {
+    for message in event.read() {
+        if let NetMessage::Fire { x, y, attack } = *message {
+            commands.spawn(PlayerAttackBundle::new(
+                PartnerAttack::new(attack),
+                texture.player_attack.clone(),
+                Vec3::new(x, y, 0.0),
+            ));
+        }
+    }
+}
+
+fn position_send(
+    player_query: Query<&Transform, With<CoopPlayer>>,
+    mut server: ResMut<Server>,
+    time: Res<Time>,
+) {
+    let pos = player_query.single().translation;
+    server.send(NetMessage::Position {
+        x: pos.x,
+        y: pos.y,
+        time: time.elapsed_seconds_f64(),
+    });
+}
+
+// 攻撃を出したら場所と種類を送る (同じフレームに出たものも1つずつ)
+fn fire_send(
+    attack_query: Query<(&Transform, &CoopPlayerAttack), Added<CoopPlayerAttack>>,
+    mut server: ResMut<Server>,
+) {
+    for (transform, attack) in &attack_query {
+        server.send(NetMessage::Fire {
+            x: transform.translation.x,
+            y: transform.translation.y,
+            attack: attack.attack(),
+        });
+    }
+}
+
+// HPか倒した数が変わったら送る
+// 変わったときしか送らないので、届くまで送り直す
+fn status_send(player_query: Query<&CoopPlayer, Changed<CoopPlayer>>, mut server: ResMut<Server>) {
+    if let Ok(player) = player_query.get_single() {
+        server.send_reliable(NetMessage::Hp(player.hp()));
+        server.send_reliable(NetMessage::Kill(player.get_kill()));
+    }
+}
+
+// ホストが作った敵を送る
+// 敵と当たった攻撃は、届かないと2人の画面が食い違うので届くまで送り直す
+fn wave_send(mut wave_event: EventReader<WaveCreated>, mut server: ResMut<Server>) {
+    for &WaveCreated { wave, seed } in wave_event.read() {
+        server.send_reliable(NetMessage::Wave { wave, seed });
+    }
+}
+
+fn hit_send(mut hit_event: EventReader<EnemyHit>, mut server: ResMut<Server>) {
+    for &EnemyHit { id, damage } in hit_event.read() {
+        server.send_reliable(NetMessage::EnemyHit { id, damage });
+    }
+}
+
+// ホストの敵が攻撃したら知らせる
+fn enemy_fire_send(mut fired_event: EventReader<EnemyFired>, mut server: ResMut<Server>) {
+    for _ in fired_event.read() {
+        server.send_reliable(NetMessage::EnemyFire);
+    }
+}
+
+// ゲストはホストの敵が攻撃したときに、自分の画面の敵から攻撃を出す
+fn enemy_fire_recv(
+    mut commands: Commands,
+    texture: Res<TextureResource>,
+    mut event: EventReader<NetMessage>,
+    enemy_query: Query<&Transform, With<Enemy>>,
+) {
+    for message in event.read() {
+        if *message == NetMessage::EnemyFire {
+            spawn_enemy_attack(&mut commands, texture.enemy_attack.clone(), &enemy_query);
+        }
+    }
+}
+
+// もう一人から届いたゲームの情報
+fn net_event(
+    mut commands: Commands,
+    texture: Res<TextureResource>,
+    mut event: EventReader<NetMessage>,
+    mut enemy_query: Query<(Entity, &mut Enemy)>,
+    mut partner: ResMut<PartnerStatus>,
+    mut update_info_event: EventWriter<UpdateInfo>,
+) {
+    for message in event.read() {
+        match *message {
+            NetMessage::Wave { wave, seed } => {
+                spawn_wave(&mut commands, texture.enemy.clone(), wave, seed)
+            }
+            NetMessage::EnemyHit { id, damage } => {
+                if let Some((entity, mut enemy)) =
+                    enemy_query.iter_mut().find(|(_, enemy)| enemy.id == id)
+                {
+                    enemy.damage(damage);
+                    if enemy.hp <= 0 {
+                        commands.entity(entity).despawn();
+                    }
+                }
+            }
+            NetMessage::Hp(hp) => {
+                partner.hp = hp;
+                update_info_event.send_default();
+            }
+            NetMessage::Kill(kill) => {
+                partner.kill = kill;
+                update_info_event.send_default();
+            }
+            _ => {}
+        }
+    }
+}
+
+// もう一人がやられたか、届かなくなったら終わる
+fn connection_check(
+    server: Res<Server>,
+    setting: Res<Setting>,
+    mut event: EventReader<NetMessage>,
+    mut main_state: ResMut<NextState<MainState>>,
+    mut game_mode: ResMut<NextState<GameMode>>,
+) {
+    let game_over = event
+        .read()
+        .any(|message| matches!(message, NetMessage::GameOver | NetMessage::Leave));
+    let silence = server.silence().as_secs_f64();
+
+    if game_over || silence > setting.disconnect_timeout + setting.reconnect_grace {
+        game_mode.set(GameMode::Disabled);
+        main_state.set(MainState::GameOver);
+    }
+}
+
+method_impl!(CoopPlayer, CoopPlayerAttack, CoopPlayerEvent);
+method_impl!(Partner, PartnerAttack);
@@ -1,26 +1,33 @@
-use std::net::{IpAddr, UdpSocket};
-use std::str;
-use std::time::Duration;
+use std::cmp::Ordering;
+use std::time::{Duration, Instant};
 
+use bevy::app::AppExit;
 use bevy::prelude::*;
 use bevy::sprite::collide_aabb::collide;
 
 use bevy::time::common_conditions::on_timer;
-use local_ip_address::local_ip;
 
-use crate::entity::{AttackMethod, PlayerAttackBundle};
-use crate::{game::*, method_impl};
-use crate::{Audio, FontResource, SoundEvent, TextureResource};
+use crate::entity::{move_player_attack, AttackMethod, PlayerAttackBundle};
+use crate::menu::MenuState;
+use crate::setting::Setting;
+use crate::{despawn_screen, game::*, method_impl};
+use crate::{Audio, FontResource, MainState, SoundEvent, TextureResource, WINDOW_WIDTH};
 
-const INITIAL_OPPONENT_POSITION: Vec2 = Vec2::new(0., 350.);
-const PLAYER_ATTACK_SPEED: f32 = 400.;
+mod cpu;
+mod hot_seat;
+mod quality;
+mod spectate;
 
-const READ_TIMEOUT: Option<Duration> = Some(Duration::from_millis(15));
+use hot_seat::HotSeat;
 
-const PLAYER_HP: Hp = 50;
-const MY_SPEED: f32 = 300.;
+const INITIAL_OPPONENT_POSITION: Vec2 = Vec2::new(0., 350.);
+// 攻撃の間隔 (シングルプレイと同じ)
+const FIRE_INTERVAL: Duration = Duration::from_millis(300);
 
-static mut SEND_TIMER: f32 = 0.0;
+// 生存確認を送る間隔
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+// 試合が始まるまでのカウントダウン
+const COUNTDOWN: Duration = Duration::from_secs(3);
 
 type Hp = isize;
 
@@ -29,33 +36,174 @@ pub struct VSPlayer;
 impl Plugin for VSPlayer {
     fn build(&self, app: &mut App) {
         app.init_resource::<Game>()
+            .add_state::<VSState>()
             .add_event::<InfoUpdate>()
-            .add_systems(OnEnter(GameMode::VS), vs_player_setup)
+            .add_event::<NetMessage>()
+            .add_systems(
+                OnEnter(GameMode::VS),
+                (
+                    net_connect.run_if(resource_exists::<Server>()),
+                    vs_player_setup,
+                ),
+            )
+            .add_systems(OnExit(GameMode::VS), vs_player_exit)
+            .add_systems(OnEnter(VSState::Countdown), round_reset)
+            .add_systems(
+                OnEnter(VSState::Finished),
+                (
+                    result_setup,
+                    center_text_hide,
+                    result_report.run_if(resource_exists::<Server>()),
+                ),
+            )
+            .add_systems(OnExit(VSState::Finished), despawn_screen::<ResultScreen>)
+            .add_systems(
+                PreUpdate,
+                (net_recv, net_tick)
+                    .run_if(resource_exists::<Server>())
+                    .run_if(in_state(GameMode::VS)),
+            )
             .add_systems(
                 Update,
                 (
                     // 自分
                     player_collision,
                     move_my_player,
-                    move_player_attack,
-                    player_attack,
+                    move_player_attack::<MyAttack>,
+                    player_attack.run_if(on_timer(FIRE_INTERVAL)),
+                    weapon_change,
+                    time_limit_check,
                     // 敵
                     opponent_collision,
                     move_opponent,
                     move_opponent_attack,
                     opponent_attack,
                     //
-                    player_pos_send
-                        .run_if(on_timer(Duration::from_secs_f32(unsafe { SEND_TIMER }))),
-                    hp_update,
+                    state_send
+                        .run_if(resource_exists::<Server>())
+                        .run_if(on_net_tick),
                     hp_recv,
+                )
+                    .run_if(in_state(VSState::Playing)),
+            )
+            .add_systems(
+                Update,
+                (
+                    countdown_system,
+                    round_text_update,
+                    local_countdown.run_if(not(resource_exists::<Server>())),
+                )
+                    .run_if(in_state(VSState::Countdown)),
+            )
+            .add_systems(
+                Update,
+                (
+                    timer_update,
+                    rematch_ack.run_if(resource_exists::<Server>()),
+                )
+                    .run_if(in_state(VSState::Countdown).or_else(in_state(VSState::Playing))),
+            )
+            .add_systems(
+                Update,
+                (
+                    final_system,
+                    local_final.run_if(not(resource_exists::<Server>())),
+                )
+                    .run_if(in_state(VSState::Playing).or_else(in_state(VSState::Ending))),
+            )
+            .add_systems(Update, round_end.run_if(in_state(VSState::Ending)))
+            .add_systems(
+                Update,
+                connection_check.run_if(resource_exists::<Server>()).run_if(
+                    in_state(VSState::Countdown)
+                        .or_else(in_state(VSState::Playing))
+                        .or_else(in_state(VSState::Ending)),
+                ),
+            )
+            .add_systems(
+                Update,
+                (
+                    rematch_button_system,
+                    menu_button_system,
+                    rematch_recv,
+                    opponent_left.run_if(resource_exists::<Server>()),
+                    local_rematch.run_if(not(resource_exists::<Server>())),
+                )
+                    .run_if(in_state(VSState::Finished)),
+            )
+            .add_systems(
+                Update,
+                (
+                    hp_update,
+                    heartbeat_send
+                        .run_if(resource_exists::<Server>())
+                        .run_if(not(resource_exists::<Spectator>()))
+                        .run_if(on_timer(HEARTBEAT_INTERVAL)),
+                    relay_fallback.run_if(resource_exists::<Server>()),
                 )
                     .run_if(in_state(GameMode::VS)),
             )
-            .add_plugins(ConnectPlugin);
+            .add_systems(
+                Last,
+                leave_send
+                    .run_if(resource_exists::<Server>())
+                    .run_if(in_state(GameMode::VS)),
+            )
+            .add_plugins((
+                ConnectPlugin,
+                cpu::CpuPlugin,
+                hot_seat::HotSeatPlugin,
+                quality::QualityPlugin,
+                spectate::SpectatePlugin,
+            ));
     }
 }
 
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, States)]
+enum VSState {
+    // 試合の始まりを待っている
+    Countdown,
+    Playing,
+    // 相手の最後の状態を待っている
+    Ending,
+    // 勝敗が決まった
+    Finished,
+    // 観戦している (自分は動かない)
+    Watching,
+    #[default]
+    Disabled,
+}
+
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+enum MatchResult {
+    Win,
+    Lose,
+    Draw,
+    // 相手がいなくなった
+    Forfeit,
+}
+
+// 接続が切れたときに表示する
+#[derive(Component)]
+struct Banner;
+
+// HPやバナーなど、VSの間だけ出しておくもの
+#[derive(Component)]
+struct VSScreen;
+
+#[derive(Component)]
+struct ResultScreen;
+
+// 再戦の状況
+#[derive(Component)]
+struct RematchText;
+
+#[derive(Component)]
+struct RematchButton;
+
+#[derive(Component)]
+struct MenuButton;
+
 #[derive(Event)]
 struct InfoUpdate {
     my: Hp,
@@ -68,21 +216,60 @@ enum InfoSection {
     Op,
 }
 
+// 試合の始まりまでの秒数
+#[derive(Component)]
+struct CountdownText;
+
+// 何本目か、何本取ったか
+#[derive(Component)]
+struct RoundText;
+
+// 今の攻撃の種類を表示する
+#[derive(Component)]
+struct WeaponText;
+
+// 残り時間を表示する
+#[derive(Component)]
+struct TimerText;
+
+#[derive(Default)]
 struct Player {
     hp: Hp,
+    // ルールの攻撃の何番目を使っているか
+    weapon: usize,
+}
+
+// 1回の対戦 (best of N) の中で取った本数
+#[derive(Default, Clone, Copy)]
+struct Score {
+    my: u32,
+    op: u32,
+    // 終わった本数 (引き分けも含む)
+    rounds: u32,
+    // 最初の試合のカウントダウンが始まった時刻
+    started: Option<Instant>,
 }
 
 #[derive(Resource, Default)]
 struct Game {
     my: Player,
     opponent: Player,
-}
-
-#[derive(Resource)]
-struct Server {
-    position: UdpSocket,
-    attack: UdpSocket,
-    info: UdpSocket,
+    // 何本目か (再戦しても増え続ける)
+    round: u32,
+    score: Score,
+    // 決着がついたときにやられていたか
+    my_final: Option<bool>,
+    op_final: Option<bool>,
+    // 再戦を申し込んだか
+    rematch: bool,
+    op_rematch: bool,
+    op_left: bool,
+    // 最後に送った決着 (相手が取りこぼしたときのために次の試合でも送り直す)
+    last_final: Option<NetMessage>,
+    // 試合が始まる (始まった) 時刻
+    start: Option<Instant>,
+    // 制限時間が過ぎた (ホストが決める)
+    time_up: bool,
 }
 
 #[derive(Component)]
@@ -91,22 +278,24 @@ struct My;
 #[derive(Component)]
 struct Opponent;
 
-#[derive(Component)]
+#[derive(Component, Clone, PartialEq)]
 struct MyAttack(Attack);
 
-#[derive(Component)]
+#[derive(Component, Clone, PartialEq)]
 struct OpponentAttack(Attack);
 
-impl Default for Player {
-    fn default() -> Self {
-        Player { hp: PLAYER_HP }
-    }
-}
-
 impl Player {
     fn damage(&mut self, power: Hp) {
         self.hp -= power
     }
+
+    // 今使っている攻撃
+    fn attack(&self, rules: &Rules) -> AttackType {
+        rules
+            .weapons
+            .get(self.weapon)
+            .map_or(AttackType::Normal, |&weapon| weapon_attack(weapon))
+    }
 }
 
 impl Game {
@@ -116,65 +305,25 @@ impl Game {
             op: self.opponent.hp,
         }
     }
-}
-
-impl Server {
-    // 座標のやり取り
-    const POSITION_PORT: &'static str = ":8000";
-    // 攻撃のやり取り
-    const ATTACK_PORT: &'static str = ":7000";
-    // ゲーム情報のやり取り
-    const INFO_PORT: &'static str = ":6000";
-
-    fn new(target_ip: IpAddr) -> Server {
-        let ip = local_ip().unwrap().to_string();
-
-        let position = UdpSocket::bind(ip.clone() + Self::POSITION_PORT).unwrap();
-        let attack = UdpSocket::bind(ip.clone() + Self::ATTACK_PORT).unwrap();
-        let info = UdpSocket::bind(ip + Self::INFO_PORT).unwrap();
 
-        let _ = position.connect(target_ip.to_string() + Self::POSITION_PORT);
-        let _ = attack.connect(target_ip.to_string() + Self::ATTACK_PORT);
-        let _ = info.connect(target_ip.to_string() + Self::INFO_PORT);
-
-        position.set_read_timeout(READ_TIMEOUT).unwrap();
-        attack.set_read_timeout(READ_TIMEOUT).unwrap();
-        info.set_read_timeout(READ_TIMEOUT).unwrap();
-
-        Server {
-            position,
-            attack,
-            info,
+    // ホストが送る両方のHP
+    fn hp_state(&self) -> NetMessage {
+        NetMessage::HpState {
+            round: self.round,
+            host: self.my.hp,
+            guest: self.opponent.hp,
         }
     }
 
-    // 座標を送る
-    #[inline]
-    fn position_send(&self, buf: &[u8]) {
-        if let Err(e) = self.position.send(buf) {
-            println!("send: {}", e);
-        }
-    }
-    // 攻撃
-    #[inline]
-    fn attack_send(&self, buf: &[u8]) {
-        if let Err(e) = self.attack.send(buf) {
-            println!("send: {}", e);
-        }
-    }
-    // ダメージを受けたら
-    #[inline]
-    fn damage_send_my(&self, buf: String) {
-        let buf = buf.to_owned() + " o";
-        if let Err(e) = self.info.send(buf.as_bytes()) {
-            println!("send: {}", e);
-        }
-    }
-    #[inline]
-    fn damage_send_opponent(&self, buf: String) {
-        let buf = buf.to_owned() + " m";
-        if let Err(e) = self.info.send(buf.as_bytes()) {
-            println!("send: {}", e);
+    // ゲスト側でホストから届いたHPにする
+    fn apply_hp_state(&mut self, message: &NetMessage) -> bool {
+        match *message {
+            NetMessage::HpState { round, host, guest } if round == self.round => {
+                self.my.hp = guest;
+                self.opponent.hp = host;
+                true
+            }
+            _ => false,
         }
     }
 }
@@ -183,10 +332,11 @@ fn vs_player_setup(
     mut commands: Commands,
     texture: Res<TextureResource>,
     font: Res<FontResource>,
-    opponent: Res<User>,
+    rules: Res<Rules>,
+    hot_seat: Option<Res<HotSeat>>,
+    mut vs_state: ResMut<NextState<VSState>>,
 ) {
-    commands.insert_resource(Server::new(opponent.ip));
-    unsafe { SEND_TIMER = opponent.delta_seconds }
+    vs_state.set(VSState::Countdown);
 
     commands.spawn((
         SpriteBundle {
@@ -220,6 +370,7 @@ fn vs_player_setup(
             ..default()
         },
         Opponent,
+        SnapshotBuffer::default(),
     ));
 
     let text_style = TextStyle {
@@ -229,50 +380,126 @@ fn vs_player_setup(
     };
 
     commands
-        .spawn(NodeBundle {
-            style: Style {
-                top: Val::Px(7.),
-                left: Val::Px(7.),
-                flex_direction: FlexDirection::Column,
-                position_type: PositionType::Absolute,
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    top: Val::Px(7.),
+                    left: Val::Px(7.),
+                    flex_direction: FlexDirection::Column,
+                    position_type: PositionType::Absolute,
+                    ..default()
+                },
                 ..default()
             },
-            ..default()
-        })
+            VSScreen,
+        ))
         .with_children(|parent| {
+            // 1台で遊ぶときはどちらのプレイヤーか分かるように
+            let labels = match hot_seat {
+                Some(_) => ["P1: ", "P2: "],
+                None => ["My: ", "Op: "],
+            };
+            for (label, section) in labels.into_iter().zip([InfoSection::My, InfoSection::Op]) {
+                parent.spawn((
+                    TextBundle::from_sections([
+                        TextSection::new(label, text_style.clone()),
+                        TextSection::new(rules.hp.to_string(), text_style.clone()),
+                    ]),
+                    section,
+                ));
+            }
+            quality::spawn_quality(parent, text_style.clone());
             parent.spawn((
                 TextBundle::from_section(
-                    "My: ".to_owned() + &PLAYER_HP.to_string(),
+                    weapon_text(Player::default().attack(&rules)),
                     text_style.clone(),
                 ),
-                InfoSection::My,
+                WeaponText,
             ));
+            parent.spawn((TextBundle::from_section("", text_style.clone()), TimerText));
+            parent.spawn((TextBundle::from_section("", text_style.clone()), RoundText));
+        });
+
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.),
+                    height: Val::Percent(100.),
+                    position_type: PositionType::Absolute,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    flex_direction: FlexDirection::Column,
+                    ..default()
+                },
+                ..default()
+            },
+            VSScreen,
+        ))
+        .with_children(|parent| {
             parent.spawn((
-                TextBundle::from_section("Op: ".to_owned() + &PLAYER_HP.to_string(), text_style),
-                InfoSection::Op,
+                TextBundle {
+                    visibility: Visibility::Hidden,
+                    ..TextBundle::from_section("", text_style.clone())
+                        .with_text_alignment(TextAlignment::Center)
+                },
+                Banner,
+            ));
+            parent.spawn((
+                TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font_size: 80.,
+                        ..text_style
+                    },
+                )
+                .with_text_alignment(TextAlignment::Center),
+                CountdownText,
             ));
         });
 }
 
+// ルールで使える攻撃を実際の攻撃にする
+fn weapon_attack(weapon: Weapon) -> AttackType {
+    match weapon {
+        Weapon::Normal => AttackType::Normal,
+        Weapon::Power => AttackType::Power,
+        Weapon::Shotgun => AttackType::Shotgun,
+        Weapon::Rebound => AttackType::Rebound(false),
+    }
+}
+
+fn weapon_text(attack_type: AttackType) -> String {
+    let name = match attack_type {
+        AttackType::Normal => "Normal",
+        AttackType::Power => "Power",
+        AttackType::Rebound(_) => "Rebound",
+        _ => "Shotgun",
+    };
+    format!("Weapon: {}", name)
+}
+
 // hpの情報を更新
 fn hp_update(mut text_query: Query<(&mut Text, &InfoSection)>, mut event: EventReader<InfoUpdate>) {
     for hp in event.read() {
         for (mut text, section) in &mut text_query {
             match section {
-                InfoSection::My => text.sections[0].value = "My: ".to_owned() + &hp.my.to_string(),
-                InfoSection::Op => text.sections[0].value = "Op: ".to_owned() + &hp.op.to_string(),
+                InfoSection::My => text.sections[1].value = hp.my.to_string(),
+                InfoSection::Op => text.sections[1].value = hp.op.to_string(),
             }
         }
     }
 }
 
 // プレイヤーに攻撃が当たると
+// HPを減らすのはホストだけで、ゲストは弾を消すだけ (HPはホストから届く)
 fn player_collision(
     mut commands: Commands,
     player_query: Query<&Transform, With<My>>,
-    attack_query: Query<(Entity, &Transform), With<OpponentAttack>>,
+    attack_query: Query<(Entity, &Transform, &OpponentAttack)>,
     mut game: ResMut<Game>,
-    server: Res<Server>,
+    mut server: Option<ResMut<Server>>,
+    role: Res<Role>,
     mut info_event: EventWriter<InfoUpdate>,
 ) {
     let transform = player_query.single();
@@ -280,7 +507,7 @@ fn player_collision(
     let player_pos = transform.translation;
     let player_size = transform.scale.xy();
 
-    for (entity, transfrom) in &attack_query {
+    for (entity, transfrom, attack) in &attack_query {
         let collision = collide(
             player_pos,
             player_size,
@@ -288,11 +515,15 @@ fn player_collision(
             transfrom.scale.xy(),
         );
         if collision.is_some() {
-            game.my.damage(1);
+            if *role == Role::Host {
+                game.my.damage(attack.attack().power());
 
-            server.damage_send_my(game.my.hp.to_string());
+                if let Some(server) = &mut server {
+                    server.send(game.hp_state());
+                }
 
-            info_event.send(game.info());
+                info_event.send(game.info());
+            }
 
             commands.entity(entity).despawn();
         }
@@ -300,12 +531,14 @@ fn player_collision(
 }
 
 // 敵に攻撃が当たると
+// ゲストは当たったように見せるだけ
 fn opponent_collision(
     mut commands: Commands,
     opponent_query: Query<&Transform, With<Opponent>>,
-    attack_query: Query<(Entity, &Transform), With<MyAttack>>,
+    attack_query: Query<(Entity, &Transform, &MyAttack)>,
     mut game: ResMut<Game>,
-    server: Res<Server>,
+    mut server: Option<ResMut<Server>>,
+    role: Res<Role>,
     mut info_event: EventWriter<InfoUpdate>,
 ) {
     let transform = opponent_query.single();
@@ -313,7 +546,7 @@ fn opponent_collision(
     let translation = transform.translation;
     let size = transform.scale.xy();
 
-    for (entity, transform) in &attack_query {
+    for (entity, transform, attack) in &attack_query {
         let collision = collide(
             translation,
             size,
@@ -321,11 +554,15 @@ fn opponent_collision(
             transform.scale.xy(),
         );
         if collision.is_some() {
-            game.opponent.damage(1);
+            if *role == Role::Host {
+                game.opponent.damage(attack.attack().power());
 
-            server.damage_send_opponent(game.opponent.hp.to_string());
+                if let Some(server) = &mut server {
+                    server.send(game.hp_state());
+                }
 
-            info_event.send(game.info());
+                info_event.send(game.info());
+            }
 
             commands.entity(entity).despawn();
         }
@@ -337,8 +574,8 @@ fn move_my_player(
     mut my_query: Query<&mut Transform, With<My>>,
     key: Res<Input<KeyCode>>,
     time: Res<Time>,
+    rules: Res<Rules>,
 ) {
-    println!("{}", time.delta_seconds());
     let translation = &mut my_query.single_mut().translation;
 
     let mut direction_x = 0.0;
@@ -357,74 +594,98 @@ fn move_my_player(
         direction_x -= 1.0
     }
 
-    let new_position_x = translation.x + direction_x * MY_SPEED * time.delta_seconds();
-    let new_position_y = translation.y + direction_y * MY_SPEED * time.delta_seconds();
+    let new_position_x = translation.x + direction_x * rules.speed * time.delta_seconds();
+    let new_position_y = translation.y + direction_y * rules.speed * time.delta_seconds();
 
     translation.x = new_position_x.clamp(-CLAMP_X, CLAMP_X);
     translation.y = new_position_y.clamp(-CLAMP_Y, CLAMP_Y);
 }
 
+// 攻撃を切り替える (シングルプレイと同じくスペースキー)
+// ルールで使える攻撃だけを順に切り替える
+fn weapon_change(
+    key: Res<Input<KeyCode>>,
+    mut game: ResMut<Game>,
+    rules: Res<Rules>,
+    mut weapon_text_query: Query<&mut Text, With<WeaponText>>,
+) {
+    if key.just_pressed(KeyCode::Space) {
+        game.my.weapon = (game.my.weapon + 1) % rules.weapons.len().max(1);
+        for mut text in &mut weapon_text_query {
+            text.sections[0].value = weapon_text(game.my.attack(&rules));
+        }
+    }
+}
+
 // プレイヤーの攻撃
 fn player_attack(
     mut commands: Commands,
     player_query: Query<&Transform, With<My>>,
     texture: Res<TextureResource>,
-    key: Res<Input<KeyCode>>,
-    server: Res<Server>,
+    game: Res<Game>,
+    rules: Res<Rules>,
+    server: Option<ResMut<Server>>,
     mut sound_event: EventWriter<SoundEvent>,
 ) {
-    if key.just_pressed(KeyCode::Space) {
-        let translation = player_query.single().translation.floor();
-        let x = translation.x;
-        let y = translation.y;
-
-        server.attack_send(format!("{} {}", x, y).as_bytes());
+    let translation = player_query.single().translation.floor();
+    let attack = game.my.attack(&rules);
+    if let Some(mut server) = server {
+        server.send(NetMessage::Fire {
+            x: translation.x,
+            y: translation.y,
+            attack,
+        });
+    }
 
+    for attack_type in attack.list() {
         commands.spawn(PlayerAttackBundle::new(
-            MyAttack::new(AttackType::Power),
+            MyAttack::new(attack_type),
             texture.player_attack.clone(),
             translation,
         ));
-
-        sound_event.send(SoundEvent(Audio::PlayerAttack));
     }
-}
 
-// 敵の攻撃
-fn opponent_attack(mut commands: Commands, texture: Res<TextureResource>, server: Res<Server>) {
-    let mut buf = [0; 9];
-    let Ok(buf_size) = server.attack.recv(&mut buf) else {
-        return;
-    };
-    commands.spawn(PlayerAttackBundle::new(
-        OpponentAttack::new(AttackType::Normal),
-        texture.player_attack.clone(),
-        to_pos(&buf[..buf_size]),
-    ));
+    sound_event.send(SoundEvent(Audio::PlayerAttack));
 }
 
-// プレイヤーの攻撃を動かす
-fn move_player_attack(
+// 敵の攻撃
+fn opponent_attack(
     mut commands: Commands,
-    mut attack_query: Query<(Entity, &mut Transform), With<MyAttack>>,
-    time: Res<Time>,
+    texture: Res<TextureResource>,
+    mut event: EventReader<NetMessage>,
 ) {
-    for (entity, mut transform) in &mut attack_query {
-        transform.translation.y += PLAYER_ATTACK_SPEED * time.delta_seconds();
-        if PLAYER_ATTACK_DESPAWN_POINT < transform.translation.y {
-            commands.entity(entity).despawn()
+    for message in event.read() {
+        if let NetMessage::Fire { x, y, attack } = *message {
+            for attack_type in attack.list() {
+                commands.spawn(PlayerAttackBundle::new(
+                    OpponentAttack::new(attack_type),
+                    texture.player_attack.clone(),
+                    to_pos(x, y),
+                ));
+            }
         }
     }
 }
 
-// 敵の攻撃を動かす
+// 敵の攻撃を動かす (相手から見た向きを反転させる)
 fn move_opponent_attack(
     mut commands: Commands,
-    mut attack_query: Query<(Entity, &mut Transform), With<OpponentAttack>>,
+    mut attack_query: Query<(Entity, &mut Transform, &mut OpponentAttack)>,
     time: Res<Time>,
 ) {
-    for (entity, mut transform) in &mut attack_query {
-        transform.translation.y -= PLAYER_ATTACK_SPEED * time.delta_seconds();
+    for (entity, mut transform, mut attack) in &mut attack_query {
+        let attack_type = attack.attack();
+
+        transform.translation.y -= attack_type.y_speed() * time.delta_seconds();
+        transform.translation.x -= attack_type.x_speed() * time.delta_seconds();
+
+        let x = transform.translation.x;
+        if attack_type == AttackType::Rebound(true) && x < -WINDOW_WIDTH / 2. {
+            *attack = OpponentAttack::new(AttackType::Rebound(false));
+        } else if attack_type == AttackType::Rebound(false) && WINDOW_WIDTH / 2. < x {
+            *attack = OpponentAttack::new(AttackType::Rebound(true));
+        }
+
         if -PLAYER_ATTACK_DESPAWN_POINT > transform.translation.y {
             commands.entity(entity).despawn()
         }
@@ -432,55 +693,647 @@ fn move_opponent_attack(
 }
 
 // 敵を動かす
-fn move_opponent(mut query: Query<&mut Transform, With<Opponent>>, server: Res<Server>) {
-    let mut buf = [0; 21];
-    let Ok(buf_size) = server.position.recv(&mut buf) else {
+fn move_opponent(
+    mut query: Query<(&mut Transform, &mut SnapshotBuffer), With<Opponent>>,
+    mut event: EventReader<NetMessage>,
+    time: Res<Time>,
+    setting: Res<Setting>,
+) {
+    let (mut transform, mut snapshots) = query.single_mut();
+    let now = time.elapsed_seconds_f64();
+
+    for message in event.read() {
+        if let NetMessage::Position { x, y, time: sent } = *message {
+            snapshots.push(sent, now, to_pos(x, y).truncate());
+        }
+    }
+    if let Some(position) = snapshots.sample(now, setting.interpolation_delay) {
+        transform.translation = position.extend(0.0);
+    }
+}
+
+// 相手から見た座標をこちらの座標に変換する
+#[inline]
+fn to_pos(x: f32, y: f32) -> Vec3 {
+    Vec3::new(-x, -y, 0.0)
+}
+
+// ティックごとに自分の位置を、ホストは両方のHPも送る
+// (ゲストが取りこぼしてもHPが食い違ったままにならないよう)
+fn state_send(
+    player_query: Query<&Transform, With<My>>,
+    mut server: ResMut<Server>,
+    game: Res<Game>,
+    role: Res<Role>,
+    time: Res<Time>,
+) {
+    let pos = player_query.single().translation;
+    server.send(NetMessage::Position {
+        x: pos.x,
+        y: pos.y,
+        time: time.elapsed_seconds_f64(),
+    });
+    if *role == Role::Host {
+        server.send(game.hp_state());
+    }
+}
+
+// ホストが決めたhpを受信する
+fn hp_recv(
+    mut game: ResMut<Game>,
+    role: Res<Role>,
+    mut event: EventReader<NetMessage>,
+    mut info_event: EventWriter<InfoUpdate>,
+) {
+    if *role == Role::Host {
+        event.clear();
+        return;
+    }
+    for message in event.read() {
+        if game.apply_hp_state(message) {
+            info_event.send(game.info());
+        }
+    }
+}
+
+// 生存確認のPingは `quality` が同じ間隔で送る
+fn heartbeat_send(mut server: ResMut<Server>, game: Res<Game>, role: Res<Role>) {
+    // 届かなかったときのために送り直す
+    if let Some(message) = &game.last_final {
+        server.send(message.clone());
+    }
+    if game.rematch {
+        server.send(NetMessage::Rematch(game.round + 1));
+    }
+
+    // カウントダウンを待っている / 数えている
+    match (*role, game.start) {
+        (Role::Guest, None) => server.send(NetMessage::Ready(game.round)),
+        (Role::Host, Some(start)) if start > Instant::now() => {
+            server.send(NetMessage::Start(game.round))
+        }
+        _ => {}
+    }
+}
+
+// ゲストの準備ができたらホストがカウントダウンを始め、ゲストに知らせる
+fn countdown_system(
+    mut game: ResMut<Game>,
+    mut server: Option<ResMut<Server>>,
+    role: Res<Role>,
+    mut event: EventReader<NetMessage>,
+    mut countdown_query: Query<(&mut Text, &mut Visibility), With<CountdownText>>,
+    mut vs_state: ResMut<NextState<VSState>>,
+) {
+    for message in event.read() {
+        match (*role, message) {
+            (Role::Host, &NetMessage::Ready(round)) if round == game.round => {
+                if game.start.is_none() {
+                    game.start = Some(Instant::now() + COUNTDOWN);
+                }
+                if let Some(server) = &mut server {
+                    server.send(NetMessage::Start(round));
+                }
+            }
+            (Role::Guest, &NetMessage::Start(round))
+                if round == game.round && game.start.is_none() =>
+            {
+                game.start = Some(Instant::now() + COUNTDOWN);
+            }
+            _ => {}
+        }
+    }
+
+    let (mut text, mut visibility) = countdown_query.single_mut();
+    let label = format!("Round {}", game.score.rounds + 1);
+    match game.start {
+        None => {
+            text.sections[0].value = format!("{}\nWaiting for opponent…", label);
+            *visibility = Visibility::Visible;
+        }
+        Some(start) if start > Instant::now() => {
+            let remaining = (start - Instant::now()).as_secs_f32().ceil();
+            text.sections[0].value = format!("{}\n{}", label, remaining);
+            *visibility = Visibility::Visible;
+        }
+        Some(_) => {
+            *visibility = Visibility::Hidden;
+            vs_state.set(VSState::Playing);
+        }
+    }
+}
+
+// 何本目かと取った本数を表示する (1本勝負なら出さない)
+fn round_text_update(
+    game: Res<Game>,
+    rules: Res<Rules>,
+    mut round_query: Query<&mut Text, With<RoundText>>,
+) {
+    if rules.best_of <= 1 {
+        return;
+    }
+    for mut text in &mut round_query {
+        text.sections[0].value = format!(
+            "Round {}/{}  {} - {}",
+            game.score.rounds + 1,
+            rules.best_of,
+            game.score.my,
+            game.score.op
+        );
+    }
+}
+
+// 相手から届かなくなったら知らせて、戻ってこなければ不戦勝にする
+fn connection_check(
+    mut commands: Commands,
+    server: Res<Server>,
+    setting: Res<Setting>,
+    mut event: EventReader<NetMessage>,
+    mut banner_query: Query<(&mut Text, &mut Visibility), With<Banner>>,
+    mut vs_state: ResMut<NextState<VSState>>,
+) {
+    let (mut text, mut visibility) = banner_query.single_mut();
+
+    let left = event.read().any(|message| *message == NetMessage::Leave);
+    let silence = server.silence().as_secs_f64();
+    let remaining = setting.disconnect_timeout + setting.reconnect_grace - silence;
+
+    if left || remaining <= 0.0 {
+        commands.insert_resource(MatchResult::Forfeit);
+        vs_state.set(VSState::Finished);
+    } else if silence >= setting.disconnect_timeout {
+        text.sections[0].value = format!("Opponent connection lost\nwaiting {}s", remaining.ceil());
+        *visibility = Visibility::Visible;
+    } else {
+        *visibility = Visibility::Hidden;
+    }
+}
+
+// 制限時間が過ぎたらホストが決着にする
+fn time_limit_check(mut game: ResMut<Game>, rules: Res<Rules>, role: Res<Role>) {
+    if *role != Role::Host || game.time_up {
+        return;
+    }
+    let (Some(limit), Some(start)) = (rules.time_limit, game.start) else {
         return;
     };
+    if start.elapsed() >= Duration::from_secs(limit as u64) {
+        game.time_up = true;
+    }
+}
 
-    let buf = &buf[..buf_size];
+// 残り時間を表示する
+fn timer_update(
+    game: Res<Game>,
+    rules: Res<Rules>,
+    mut timer_query: Query<&mut Text, With<TimerText>>,
+) {
+    let (Some(limit), Some(start)) = (rules.time_limit, game.start) else {
+        return;
+    };
+    let remaining = Duration::from_secs(limit as u64).saturating_sub(start.elapsed());
+    for mut text in &mut timer_query {
+        text.sections[0].value = format!("Time: {}", remaining.as_secs_f32().ceil());
+    }
+}
 
-    println!("{}", str::from_utf8(buf).unwrap());
+// 自分のHPが0になるか、時間切れか、相手から決着を知らされたら最後の状態を送り合う
+// お互いに同じ2つの値から勝敗を決めるので、結果は食い違わない
+// 時間切れのときはHPが少ない方がやられたことにする
+fn final_system(
+    mut game: ResMut<Game>,
+    server: Option<ResMut<Server>>,
+    role: Res<Role>,
+    mut event: EventReader<NetMessage>,
+    mut vs_state: ResMut<NextState<VSState>>,
+    mut info_event: EventWriter<InfoUpdate>,
+) {
+    for message in event.read() {
+        match *message {
+            NetMessage::Final {
+                round,
+                down,
+                time_up,
+            } if round == game.round => {
+                game.op_final = Some(down);
+                game.time_up |= time_up;
+            }
+            // 決着の直前に届いたHPも反映してから決める
+            NetMessage::HpState { .. } if *role == Role::Guest && game.apply_hp_state(message) => {
+                info_event.send(game.info());
+            }
+            _ => {}
+        }
+    }
 
-    query.single_mut().translation = to_pos(buf);
+    if game.my_final.is_none() && (game.my.hp <= 0 || game.time_up || game.op_final.is_some()) {
+        // ここから先は自分のHPを変えない
+        let down = game.my.hp <= 0 || (game.time_up && game.my.hp < game.opponent.hp);
+        let message = NetMessage::Final {
+            round: game.round,
+            down,
+            time_up: game.time_up,
+        };
+        if let Some(mut server) = server {
+            server.send(message.clone());
+        }
+        game.my_final = Some(down);
+        game.last_final = Some(message);
+        vs_state.set(VSState::Ending);
+    }
 }
 
-// bufを座標に変換する
-#[inline]
-fn to_pos(buf: &[u8]) -> Vec3 {
-    let str = str::from_utf8(buf).unwrap();
-    let pos: Vec<&str> = str.split_whitespace().collect();
+// お互いの最後の状態がそろったら1本の勝敗を決め、
+// どちらかが過半数を取るか最後の1本が終わったら対戦の結果にする
+fn round_end(
+    mut commands: Commands,
+    mut game: ResMut<Game>,
+    rules: Res<Rules>,
+    mut vs_state: ResMut<NextState<VSState>>,
+) {
+    let (Some(my), Some(op)) = (game.my_final, game.op_final) else {
+        return;
+    };
 
-    let x: f32 = pos[0].trim().parse().unwrap();
-    let y: f32 = pos[1].trim().parse().unwrap();
+    let score = &mut game.score;
+    score.rounds += 1;
+    match (my, op) {
+        (true, false) => score.op += 1,
+        (false, true) => score.my += 1,
+        _ => {}
+    }
 
-    Vec3::new(-x, -y, 0.0)
+    let needed = rules.best_of / 2 + 1;
+    if score.my >= needed || score.op >= needed || score.rounds >= rules.best_of {
+        commands.insert_resource(match score.my.cmp(&score.op) {
+            Ordering::Greater => MatchResult::Win,
+            Ordering::Less => MatchResult::Lose,
+            Ordering::Equal => MatchResult::Draw,
+        });
+        vs_state.set(VSState::Finished);
+    } else {
+        game.round += 1;
+        vs_state.set(VSState::Countdown);
+    }
 }
 
-fn player_pos_send(player_query: Query<&Transform, With<My>>, server: Res<Server>) {
-    let pos = player_query.single().translation;
-    let pos = format!("{} {}", pos.x, pos.y);
-    server.position_send(pos.as_bytes());
+type PlayerEntity = Or<(With<My>, With<Opponent>)>;
+type AttackEntity = Or<(With<MyAttack>, With<OpponentAttack>)>;
+
+// 試合の始め (再戦のときも) に状態を戻す
+fn round_reset(
+    mut commands: Commands,
+    mut game: ResMut<Game>,
+    rules: Res<Rules>,
+    mut player_query: Query<(&mut Transform, Option<&mut SnapshotBuffer>), PlayerEntity>,
+    attack_query: Query<Entity, AttackEntity>,
+    mut banner_query: Query<&mut Visibility, With<Banner>>,
+    mut info_event: EventWriter<InfoUpdate>,
+) {
+    // 攻撃の種類と取った本数は次の試合でもそのまま
+    let last_final = game.last_final.take();
+    *game = Game {
+        my: Player {
+            hp: rules.hp,
+            weapon: game.my.weapon,
+        },
+        opponent: Player {
+            hp: rules.hp,
+            weapon: game.opponent.weapon,
+        },
+        round: game.round,
+        score: Score {
+            started: game.score.started.or_else(|| Some(Instant::now())),
+            ..game.score
+        },
+        last_final,
+        ..default()
+    };
+    commands.remove_resource::<MatchResult>();
+
+    for (mut transform, snapshots) in &mut player_query {
+        transform.translation = match snapshots {
+            Some(mut snapshots) => {
+                *snapshots = SnapshotBuffer::default();
+                INITIAL_OPPONENT_POSITION.extend(0.)
+            }
+            None => INITIAL_PLAYER_POSITION.extend(0.),
+        };
+    }
+    for entity in &attack_query {
+        commands.entity(entity).despawn();
+    }
+    for mut visibility in &mut banner_query {
+        *visibility = Visibility::Hidden;
+    }
+
+    info_event.send(game.info());
 }
 
-// hpを受信する
-fn hp_recv(mut game: ResMut<Game>, server: Res<Server>, mut event: EventWriter<InfoUpdate>) {
-    let mut buf = [0; 6];
-    let Ok(buf_size) = server.info.recv(&mut buf) else {
+// ネットワークの相手がいなければすぐ数え始める
+fn local_countdown(mut game: ResMut<Game>) {
+    if game.start.is_none() {
+        game.start = Some(Instant::now() + COUNTDOWN);
+    }
+}
+
+// ネットワークの相手がいなければ相手の決着もこちらで決める (決め方は同じ)
+fn local_final(mut game: ResMut<Game>) {
+    if game.op_final.is_none() && (game.opponent.hp <= 0 || game.time_up || game.my_final.is_some())
+    {
+        let down = game.opponent.hp <= 0 || (game.time_up && game.opponent.hp < game.my.hp);
+        game.op_final = Some(down);
+    }
+}
+
+// ネットワークの相手がいなければいつでも再戦する
+fn local_rematch(mut game: ResMut<Game>) {
+    if game.rematch {
+        game.op_rematch = true;
+    }
+}
+
+type CenterText = Or<(With<Banner>, With<CountdownText>)>;
+
+fn center_text_hide(mut text_query: Query<&mut Visibility, CenterText>) {
+    for mut visibility in &mut text_query {
+        *visibility = Visibility::Hidden;
+    }
+}
+
+fn result_setup(
+    mut commands: Commands,
+    font: Res<FontResource>,
+    result: Res<MatchResult>,
+    opponent: Res<User>,
+    game: Res<Game>,
+    rules: Res<Rules>,
+    hot_seat: Option<Res<HotSeat>>,
+) {
+    let text_style = |font_size| TextStyle {
+        font: font.0.clone(),
+        font_size,
+        color: Color::WHITE,
+    };
+    let button_bundle = ButtonBundle {
+        style: Style {
+            width: Val::Px(250.),
+            height: Val::Px(70.),
+            margin: UiRect::vertical(Val::Px(20.)),
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            ..default()
+        },
+        ..default()
+    };
+    let button_text_style = TextStyle {
+        color: Color::BLACK,
+        ..text_style(50.)
+    };
+
+    let title = match (*result, hot_seat.is_some()) {
+        (MatchResult::Win, true) => "Player 1 wins!",
+        (MatchResult::Lose, true) => "Player 2 wins!",
+        (MatchResult::Win, false) => "You win!",
+        (MatchResult::Lose, false) => "You lose",
+        (MatchResult::Draw, _) => "Draw",
+        (MatchResult::Forfeit, _) => "Opponent left\nYou win!",
+    };
+
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.),
+                    height: Val::Percent(100.),
+                    position_type: PositionType::Absolute,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    flex_direction: FlexDirection::Column,
+                    ..default()
+                },
+                background_color: Color::rgba(0., 0., 0., 0.7).into(),
+                z_index: ZIndex::Global(1),
+                ..default()
+            },
+            ResultScreen,
+        ))
+        .with_children(|parent| {
+            parent.spawn(
+                TextBundle::from_section(title, text_style(100.))
+                    .with_text_alignment(TextAlignment::Center),
+            );
+            if hot_seat.is_none() {
+                parent.spawn(TextBundle::from_section(
+                    format!("vs {}", opponent.name),
+                    text_style(50.),
+                ));
+            }
+            if rules.best_of > 1 {
+                parent.spawn(TextBundle::from_section(
+                    format!("{} - {}", game.score.my, game.score.op),
+                    text_style(50.),
+                ));
+            }
+            parent.spawn((TextBundle::from_section("", text_style(40.)), RematchText));
+
+            // いなくなった相手とは再戦できない
+            if *result != MatchResult::Forfeit {
+                parent
+                    .spawn((button_bundle.clone(), RematchButton))
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle::from_section(
+                            "Rematch",
+                            button_text_style.clone(),
+                        ));
+                    });
+            }
+            parent
+                .spawn((button_bundle, MenuButton))
+                .with_children(|parent| {
+                    parent.spawn(TextBundle::from_section("Menu", button_text_style));
+                });
+        });
+}
+
+// ホストが対戦の結果を部屋サーバーに記録してもらう
+// 相手がいなくなったときはホストの勝ちにする
+fn result_report(
+    game: Res<Game>,
+    result: Res<MatchResult>,
+    role: Res<Role>,
+    room: Res<RoomRequest>,
+    opponent: Res<User>,
+    mut event: EventWriter<MatchFinished>,
+) {
+    if *role != Role::Host {
         return;
+    }
+
+    let host = room.user.name.clone();
+    let guest = opponent.name.clone();
+    let winner = match *result {
+        MatchResult::Win | MatchResult::Forfeit => Some(host.clone()),
+        MatchResult::Lose => Some(guest.clone()),
+        MatchResult::Draw => None,
     };
+    event.send(MatchFinished {
+        round: game.round,
+        record: MatchRecord {
+            host,
+            guest,
+            winner,
+            duration: game
+                .score
+                .started
+                .map_or(0, |start| start.elapsed().as_secs()),
+            host_hp: game.my.hp,
+            guest_hp: game.opponent.hp,
+            finished: 0,
+        },
+    });
+}
+
+fn rematch_button_system(
+    interaction: Query<&Interaction, (Changed<Interaction>, With<RematchButton>)>,
+    mut game: ResMut<Game>,
+    mut server: Option<ResMut<Server>>,
+    mut rematch_text_query: Query<&mut Text, With<RematchText>>,
+) {
+    for interaction in &interaction {
+        if *interaction == Interaction::Pressed && !game.rematch {
+            game.rematch = true;
+            if let Some(server) = &mut server {
+                server.send(NetMessage::Rematch(game.round + 1));
+            }
+
+            for mut text in &mut rematch_text_query {
+                text.sections[0].value = "Waiting for opponent…".to_string();
+            }
+        }
+    }
+}
+
+fn menu_button_system(
+    interaction: Query<&Interaction, (Changed<Interaction>, With<MenuButton>)>,
+    mut game_mode: ResMut<NextState<GameMode>>,
+    mut main_state: ResMut<NextState<MainState>>,
+    mut menu_state: ResMut<NextState<MenuState>>,
+) {
+    for interaction in &interaction {
+        if *interaction == Interaction::Pressed {
+            game_mode.set(GameMode::Disabled);
+            main_state.set(MainState::Menu);
+            menu_state.set(MenuState::Main);
+        }
+    }
+}
 
-    let str = str::from_utf8(&buf[..buf_size]).unwrap();
+// お互いに再戦を申し込んだら次の試合を始める
+fn rematch_recv(
+    mut game: ResMut<Game>,
+    mut event: EventReader<NetMessage>,
+    mut rematch_text_query: Query<&mut Text, With<RematchText>>,
+    mut vs_state: ResMut<NextState<VSState>>,
+) {
+    for message in event.read() {
+        match *message {
+            NetMessage::Rematch(round) | NetMessage::RematchAck(round)
+                if round == game.round + 1 =>
+            {
+                game.op_rematch = true;
+                if !game.rematch {
+                    for mut text in &mut rematch_text_query {
+                        text.sections[0].value = "Opponent wants a rematch".to_string();
+                    }
+                }
+            }
+            NetMessage::Leave => game.op_left = true,
+            _ => {}
+        }
+    }
 
-    let mut vec: Vec<&str> = str.split_whitespace().collect();
-    let hp: Hp = vec[0].trim().parse().unwrap();
-    match vec.pop().unwrap() {
-        "m" => game.my.hp = hp,
-        "o" => game.opponent.hp = hp,
-        _ => panic!(),
+    if game.rematch && game.op_rematch && !game.op_left {
+        game.round += 1;
+        game.score = Score::default();
+        vs_state.set(VSState::Countdown);
     }
+}
+
+// 先に始めた側は、申し込みが届かなかった相手に始めたことを伝える
+fn rematch_ack(game: Res<Game>, mut server: ResMut<Server>, mut event: EventReader<NetMessage>) {
+    for message in event.read() {
+        if *message == NetMessage::Rematch(game.round) {
+            server.send(NetMessage::RematchAck(game.round));
+        }
+    }
+}
 
-    event.send(game.info());
+// 結果画面で相手がいなくなったら再戦できなくする
+fn opponent_left(
+    mut commands: Commands,
+    mut game: ResMut<Game>,
+    server: Res<Server>,
+    setting: Res<Setting>,
+    button_query: Query<Entity, With<RematchButton>>,
+    mut rematch_text_query: Query<&mut Text, With<RematchText>>,
+) {
+    let silence = server.silence().as_secs_f64();
+    if button_query.is_empty()
+        || !game.op_left && silence <= setting.disconnect_timeout + setting.reconnect_grace
+    {
+        return;
+    }
+
+    game.op_left = true;
+    game.rematch = false;
+    for entity in &button_query {
+        commands.entity(entity).despawn_recursive();
+    }
+    for mut text in &mut rematch_text_query {
+        text.sections[0].value = "Opponent left".to_string();
+    }
+}
+
+type VSEntity = Or<(
+    With<My>,
+    With<Opponent>,
+    With<MyAttack>,
+    With<OpponentAttack>,
+    With<VSScreen>,
+)>;
+
+// VSをやめるときに相手に伝えて片付ける
+fn vs_player_exit(
+    mut commands: Commands,
+    server: Option<ResMut<Server>>,
+    entity_query: Query<Entity, VSEntity>,
+    mut vs_state: ResMut<NextState<VSState>>,
+) {
+    if let Some(mut server) = server {
+        server.send(NetMessage::Leave);
+    }
+
+    for entity in &entity_query {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    commands.remove_resource::<Server>();
+    commands.remove_resource::<Relay>();
+    commands.remove_resource::<SessionKey>();
+    commands.remove_resource::<Rules>();
+    commands.remove_resource::<Role>();
+    commands.remove_resource::<MatchResult>();
+    commands.insert_resource(Game::default());
+    vs_state.set(VSState::Disabled);
+}
+
+// 終了するときに相手に伝える
+fn leave_send(mut exit_event: EventReader<AppExit>, mut server: ResMut<Server>) {
+    if !exit_event.is_empty() {
+        exit_event.clear();
+        server.send(NetMessage::Leave);
+    }
 }
 
 method_impl!(My, MyAttack);
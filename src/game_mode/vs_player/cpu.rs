@@ -0,0 +1,273 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use rand::{thread_rng, Rng};
+
+use super::{
+    weapon_attack, My, MyAttack, Opponent, OpponentAttack, VSState, INITIAL_OPPONENT_POSITION,
+};
+use crate::entity::{AttackMethod, PlayerAttackBundle};
+use crate::game::*;
+use crate::menu::{BUTTON_HEIGHT, BUTTON_WIDTH};
+use crate::{despawn_screen, FontResource, TextureResource};
+
+const DIFFICULTY_LIST: [(&str, Difficulty); 3] = [
+    ("Easy", Difficulty::Easy),
+    ("Normal", Difficulty::Normal),
+    ("Hard", Difficulty::Hard),
+];
+
+/// ネットワークを使わずにCPUとVSで対戦する
+pub(super) struct CpuPlugin;
+
+impl Plugin for CpuPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(GameMode::Cpu), cpu_menu_setup)
+            .add_systems(OnExit(GameMode::Cpu), despawn_screen::<CpuMenuScreen>)
+            .add_systems(
+                Update,
+                difficulty_button_system.run_if(in_state(GameMode::Cpu)),
+            )
+            .add_systems(OnExit(GameMode::VS), cpu_exit)
+            .add_systems(
+                Update,
+                (cpu_move, cpu_attack)
+                    .run_if(resource_exists::<Cpu>())
+                    .run_if(in_state(VSState::Playing)),
+            );
+    }
+}
+
+#[derive(Component)]
+struct CpuMenuScreen;
+
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    // ルールの速さに対する割合
+    fn speed(self) -> f32 {
+        match self {
+            Difficulty::Easy => 0.5,
+            Difficulty::Normal => 0.75,
+            Difficulty::Hard => 1.0,
+        }
+    }
+
+    fn fire_interval(self) -> Duration {
+        match self {
+            Difficulty::Easy => Duration::from_millis(900),
+            Difficulty::Normal => Duration::from_millis(600),
+            Difficulty::Hard => Duration::from_millis(350),
+        }
+    }
+
+    // この距離まで近づいた弾を避ける
+    fn sight(self) -> f32 {
+        match self {
+            Difficulty::Easy => 120.,
+            Difficulty::Normal => 250.,
+            Difficulty::Hard => 400.,
+        }
+    }
+
+    // 狙いのずれの大きさ
+    fn aim_error(self) -> f32 {
+        match self {
+            Difficulty::Easy => 150.,
+            Difficulty::Normal => 70.,
+            Difficulty::Hard => 20.,
+        }
+    }
+}
+
+/// 相手をCPUが動かしている
+#[derive(Resource)]
+struct Cpu {
+    difficulty: Difficulty,
+    fire: Timer,
+    // 今の狙いのずれ (撃つたびに変える)
+    aim: f32,
+}
+
+impl Cpu {
+    fn new(difficulty: Difficulty) -> Cpu {
+        Cpu {
+            difficulty,
+            fire: Timer::new(difficulty.fire_interval(), TimerMode::Repeating),
+            aim: 0.,
+        }
+    }
+}
+
+fn cpu_menu_setup(mut commands: Commands, font: Res<FontResource>) {
+    let button_style = Style {
+        width: BUTTON_WIDTH,
+        height: BUTTON_HEIGHT,
+        margin: UiRect::all(Val::Px(20.)),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..default()
+    };
+    let text_style = |font_size| TextStyle {
+        font: font.0.clone(),
+        font_size,
+        color: Color::BLUE,
+    };
+
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.),
+                    height: Val::Percent(100.),
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                ..default()
+            },
+            CpuMenuScreen,
+        ))
+        .with_children(|parent| {
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .with_children(|parent| {
+                    parent.spawn(TextBundle::from_section("VS CPU", text_style(100.)));
+
+                    for (text, difficulty) in DIFFICULTY_LIST {
+                        parent
+                            .spawn((
+                                ButtonBundle {
+                                    style: button_style.clone(),
+                                    ..default()
+                                },
+                                difficulty,
+                            ))
+                            .with_children(|parent| {
+                                parent.spawn(TextBundle::from_section(text, text_style(60.)));
+                            });
+                    }
+                });
+        });
+}
+
+// 強さを選んだらホストとしてVSを始める
+fn difficulty_button_system(
+    mut commands: Commands,
+    interaction: Query<(&Interaction, &Difficulty), Changed<Interaction>>,
+    mut game_mode: ResMut<NextState<GameMode>>,
+) {
+    for (interaction, difficulty) in &interaction {
+        if *interaction == Interaction::Pressed {
+            let name = format!("CPU ({:?})", difficulty);
+            commands.insert_resource(User::new(&name, IpAddr::V4(Ipv4Addr::LOCALHOST)));
+            commands.insert_resource(Cpu::new(*difficulty));
+            commands.insert_resource(Role::Host);
+            commands.insert_resource(Rules::default());
+
+            game_mode.set(GameMode::VS);
+        }
+    }
+}
+
+fn cpu_exit(mut commands: Commands) {
+    commands.remove_resource::<Cpu>();
+}
+
+// 近づいてくる弾を避け、いなければプレイヤーの上に回り込む
+fn cpu_move(
+    cpu: Res<Cpu>,
+    mut cpu_query: Query<&mut Transform, With<Opponent>>,
+    my_query: Query<&Transform, (With<My>, Without<Opponent>)>,
+    attack_query: Query<&Transform, (With<MyAttack>, Without<Opponent>)>,
+    rules: Res<Rules>,
+    time: Res<Time>,
+) {
+    let translation = &mut cpu_query.single_mut().translation;
+    let target = my_query.single().translation;
+    let sight = cpu.difficulty.sight();
+
+    // 当たりそうな弾のうち一番近いもの
+    let threat = attack_query
+        .iter()
+        .map(|transform| transform.translation)
+        .filter(|attack| {
+            attack.y < translation.y
+                && translation.y - attack.y < sight
+                && (attack.x - translation.x).abs() < PLAYER_SIZE.x
+        })
+        .max_by(|a, b| a.y.total_cmp(&b.y));
+
+    let direction_x = match threat {
+        Some(attack) => {
+            let away = if attack.x < translation.x { 1.0 } else { -1.0 };
+            // 端に追い詰められたら反対に抜ける
+            if (translation.x + away * PLAYER_SIZE.x).abs() > CLAMP_X {
+                -away
+            } else {
+                away
+            }
+        }
+        None => step(target.x + cpu.aim - translation.x),
+    };
+    let direction_y = step(INITIAL_OPPONENT_POSITION.y - translation.y);
+
+    let speed = rules.speed * cpu.difficulty.speed() * time.delta_seconds();
+    translation.x = (translation.x + direction_x * speed).clamp(-CLAMP_X, CLAMP_X);
+    translation.y = (translation.y + direction_y * speed).clamp(-CLAMP_Y, CLAMP_Y);
+}
+
+// 近ければ止まる
+#[inline]
+fn step(distance: f32) -> f32 {
+    if distance.abs() < 5. {
+        0.
+    } else {
+        distance.signum()
+    }
+}
+
+// ルールで使える攻撃から選んで撃つ
+fn cpu_attack(
+    mut commands: Commands,
+    mut cpu: ResMut<Cpu>,
+    cpu_query: Query<&Transform, With<Opponent>>,
+    texture: Res<TextureResource>,
+    rules: Res<Rules>,
+    time: Res<Time>,
+) {
+    if !cpu.fire.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let mut rng = thread_rng();
+    let translation = cpu_query.single().translation.floor();
+    let attack = match rules.weapons.len() {
+        0 => AttackType::Normal,
+        len => weapon_attack(rules.weapons[rng.gen_range(0..len)]),
+    };
+
+    for attack_type in attack.list() {
+        commands.spawn(PlayerAttackBundle::new(
+            OpponentAttack::new(attack_type),
+            texture.player_attack.clone(),
+            translation,
+        ));
+    }
+
+    let error = cpu.difficulty.aim_error();
+    cpu.aim = rng.gen_range(-error..=error);
+}
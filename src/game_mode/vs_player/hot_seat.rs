@@ -0,0 +1,149 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use bevy::prelude::*;
+use bevy::time::common_conditions::on_timer;
+
+use super::{weapon_text, Game, Opponent, OpponentAttack, VSScreen, VSState, FIRE_INTERVAL};
+use crate::entity::{AttackMethod, PlayerAttackBundle};
+use crate::game::*;
+use crate::{FontResource, TextureResource};
+
+/// 1台のキーボードで2人がVSで対戦する
+/// Player1は自分 (矢印キーとスペース)、Player2は相手 (WASDとQ)
+pub(super) struct HotSeatPlugin;
+
+impl Plugin for HotSeatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(GameMode::HotSeat), hot_seat_start)
+            .add_systems(
+                OnEnter(GameMode::VS),
+                player2_hud_setup.run_if(resource_exists::<HotSeat>()),
+            )
+            .add_systems(OnExit(GameMode::VS), hot_seat_exit)
+            .add_systems(
+                Update,
+                (
+                    move_player2,
+                    player2_attack.run_if(on_timer(FIRE_INTERVAL)),
+                    player2_weapon_change,
+                )
+                    .run_if(resource_exists::<HotSeat>())
+                    .run_if(in_state(VSState::Playing)),
+            );
+    }
+}
+
+/// 相手をもう1人のプレイヤーが動かしている
+#[derive(Resource)]
+pub(super) struct HotSeat;
+
+#[derive(Component)]
+struct Player2WeaponText;
+
+// ネットワークを使わずにホストとしてVSを始める
+fn hot_seat_start(mut commands: Commands, mut game_mode: ResMut<NextState<GameMode>>) {
+    commands.insert_resource(User::new("Player 2", IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    commands.insert_resource(HotSeat);
+    commands.insert_resource(Role::Host);
+    commands.insert_resource(Rules::default());
+
+    game_mode.set(GameMode::VS);
+}
+
+fn hot_seat_exit(mut commands: Commands) {
+    commands.remove_resource::<HotSeat>();
+}
+
+// Player2の攻撃の種類は右上に出す
+fn player2_hud_setup(mut commands: Commands, font: Res<FontResource>, rules: Res<Rules>) {
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    top: Val::Px(7.),
+                    right: Val::Px(7.),
+                    position_type: PositionType::Absolute,
+                    ..default()
+                },
+                ..default()
+            },
+            VSScreen,
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                TextBundle::from_section(
+                    weapon_text(Game::default().opponent.attack(&rules)),
+                    TextStyle {
+                        font: font.0.clone(),
+                        font_size: 40.0,
+                        color: Color::WHITE,
+                    },
+                ),
+                Player2WeaponText,
+            ));
+        });
+}
+
+fn move_player2(
+    mut player2_query: Query<&mut Transform, With<Opponent>>,
+    key: Res<Input<KeyCode>>,
+    time: Res<Time>,
+    rules: Res<Rules>,
+) {
+    let translation = &mut player2_query.single_mut().translation;
+
+    let mut direction_x = 0.0;
+    let mut direction_y = 0.0;
+
+    if key.pressed(KeyCode::W) {
+        direction_y += 1.0
+    }
+    if key.pressed(KeyCode::S) {
+        direction_y -= 1.0
+    }
+    if key.pressed(KeyCode::D) {
+        direction_x += 1.0
+    }
+    if key.pressed(KeyCode::A) {
+        direction_x -= 1.0
+    }
+
+    let new_position_x = translation.x + direction_x * rules.speed * time.delta_seconds();
+    let new_position_y = translation.y + direction_y * rules.speed * time.delta_seconds();
+
+    translation.x = new_position_x.clamp(-CLAMP_X, CLAMP_X);
+    translation.y = new_position_y.clamp(-CLAMP_Y, CLAMP_Y);
+}
+
+fn player2_weapon_change(
+    key: Res<Input<KeyCode>>,
+    mut game: ResMut<Game>,
+    rules: Res<Rules>,
+    mut weapon_text_query: Query<&mut Text, With<Player2WeaponText>>,
+) {
+    if key.just_pressed(KeyCode::Q) {
+        game.opponent.weapon = (game.opponent.weapon + 1) % rules.weapons.len().max(1);
+        for mut text in &mut weapon_text_query {
+            text.sections[0].value = weapon_text(game.opponent.attack(&rules));
+        }
+    }
+}
+
+// Player2の攻撃は相手の攻撃として下に向かって飛ぶ
+fn player2_attack(
+    mut commands: Commands,
+    player2_query: Query<&Transform, With<Opponent>>,
+    texture: Res<TextureResource>,
+    game: Res<Game>,
+    rules: Res<Rules>,
+) {
+    let translation = player2_query.single().translation.floor();
+
+    for attack_type in game.opponent.attack(&rules).list() {
+        commands.spawn(PlayerAttackBundle::new(
+            OpponentAttack::new(attack_type),
+            texture.player_attack.clone(),
+            translation,
+        ));
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use bevy::time::common_conditions::on_timer;
+
+use super::HEARTBEAT_INTERVAL;
+use crate::game::*;
+
+// 返事がこれより遅ければ届かなかったとみなす
+const PONG_TIMEOUT: Duration = Duration::from_secs(1);
+// 届かなかった割合はこの回数のPingから数える
+const LOSS_WINDOW: usize = 20;
+
+// これを超えたら黄色、その次を超えたら赤 (往復時間とばらつきは秒)
+const RTT_LIMITS: (f64, f64) = (0.15, 0.3);
+const JITTER_LIMITS: (f64, f64) = (0.03, 0.08);
+const LOSS_LIMITS: (f64, f64) = (0.05, 0.2);
+
+/// VSで相手とPingをやり取りして、回線の状態を表示する
+pub(super) struct QualityPlugin;
+
+impl Plugin for QualityPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(GameMode::VS), quality_setup)
+            .add_systems(OnExit(GameMode::VS), quality_exit)
+            .add_systems(
+                Update,
+                (
+                    ping_send.run_if(on_timer(HEARTBEAT_INTERVAL)),
+                    pong_recv,
+                    quality_update,
+                )
+                    .run_if(resource_exists::<Server>())
+                    .run_if(not(resource_exists::<Spectator>()))
+                    .run_if(in_state(GameMode::VS)),
+            );
+    }
+}
+
+// 送ったPingと返事が来るまでの時間
+struct PingRecord {
+    id: u32,
+    sent: Instant,
+    rtt: Option<Duration>,
+}
+
+#[derive(Resource, Default)]
+struct Quality {
+    next_id: u32,
+    // 古い順に `LOSS_WINDOW` 回分
+    pings: VecDeque<PingRecord>,
+    // 最後に測った往復時間と、そのばらつき (秒)
+    rtt: Option<f64>,
+    jitter: f64,
+}
+
+impl Quality {
+    // 送るPingを記録して、その番号を返す
+    fn ping(&mut self, now: Instant) -> u32 {
+        let id = self.next_id;
+        self.next_id = id.wrapping_add(1);
+        self.pings.push_back(PingRecord {
+            id,
+            sent: now,
+            rtt: None,
+        });
+        if self.pings.len() > LOSS_WINDOW {
+            self.pings.pop_front();
+        }
+        id
+    }
+
+    // 返事から往復時間を測る (`PONG_TIMEOUT` より遅い返事は届かなかったものとして扱う)
+    fn pong(&mut self, id: u32, now: Instant) {
+        let Some(ping) = self
+            .pings
+            .iter_mut()
+            .find(|ping| ping.id == id && ping.rtt.is_none())
+        else {
+            return;
+        };
+        let rtt = now.saturating_duration_since(ping.sent);
+        if rtt > PONG_TIMEOUT {
+            return;
+        }
+        ping.rtt = Some(rtt);
+
+        // ばらつきは前の往復時間との差を均したもの (RFC 3550と同じ)
+        let rtt = rtt.as_secs_f64();
+        if let Some(last) = self.rtt {
+            self.jitter += ((rtt - last).abs() - self.jitter) / 16.;
+        }
+        self.rtt = Some(rtt);
+    }
+
+    // 届かなかった割合 (まだ待っているものは数えない)
+    fn loss(&self, now: Instant) -> f64 {
+        let (lost, total) = self
+            .pings
+            .iter()
+            .filter(|ping| {
+                ping.rtt.is_some() || now.saturating_duration_since(ping.sent) > PONG_TIMEOUT
+            })
+            .fold((0, 0), |(lost, total), ping| {
+                (lost + ping.rtt.is_none() as usize, total + 1)
+            });
+        if total == 0 {
+            0.
+        } else {
+            lost as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Signal {
+    Good,
+    Fair,
+    Poor,
+}
+
+impl Signal {
+    fn new(value: f64, (fair, poor): (f64, f64)) -> Signal {
+        if value > poor {
+            Signal::Poor
+        } else if value > fair {
+            Signal::Fair
+        } else {
+            Signal::Good
+        }
+    }
+
+    fn bars(self) -> &'static str {
+        match self {
+            Signal::Good => "[###]",
+            Signal::Fair => "[##-]",
+            Signal::Poor => "[#--]",
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Signal::Good => Color::GREEN,
+            Signal::Fair => Color::YELLOW,
+            Signal::Poor => Color::RED,
+        }
+    }
+}
+
+// 往復時間などの表示 (HPの下に出す)
+#[derive(Component)]
+struct QualityText;
+
+/// `InfoSection` の下に置く (ネットワークを使わないときは空のまま)
+pub(super) fn spawn_quality(parent: &mut ChildBuilder, text_style: TextStyle) {
+    let text_style = TextStyle {
+        font_size: 25.,
+        ..text_style
+    };
+    parent.spawn((
+        TextBundle::from_sections([
+            TextSection::new("", text_style.clone()),
+            TextSection::new("", text_style.clone()),
+            TextSection::new("", text_style),
+        ]),
+        QualityText,
+    ));
+}
+
+fn quality_setup(mut commands: Commands) {
+    commands.insert_resource(Quality::default());
+}
+
+fn quality_exit(mut commands: Commands) {
+    commands.remove_resource::<Quality>();
+}
+
+// 生存確認も兼ねる
+fn ping_send(mut server: ResMut<Server>, mut quality: ResMut<Quality>) {
+    let id = quality.ping(Instant::now());
+    server.send(NetMessage::Ping(id));
+}
+
+// 相手のPingにはすぐ返し、自分のPingの返事で往復時間を測る
+fn pong_recv(
+    mut event: EventReader<NetMessage>,
+    mut server: ResMut<Server>,
+    mut quality: ResMut<Quality>,
+) {
+    for message in event.read() {
+        match *message {
+            NetMessage::Ping(id) => server.send(NetMessage::Pong(id)),
+            NetMessage::Pong(id) => quality.pong(id, Instant::now()),
+            _ => {}
+        }
+    }
+}
+
+fn quality_update(quality: Res<Quality>, mut text_query: Query<&mut Text, With<QualityText>>) {
+    let Some(rtt) = quality.rtt else {
+        for mut text in &mut text_query {
+            text.sections[0].value = "[---]".to_string();
+            text.sections[0].style.color = Color::GRAY;
+            text.sections[1].value = " ping: -".to_string();
+        }
+        return;
+    };
+    let loss = quality.loss(Instant::now());
+
+    let rtt_signal = Signal::new(rtt, RTT_LIMITS);
+    let jitter_signal = Signal::new(quality.jitter, JITTER_LIMITS);
+    let loss_signal = Signal::new(loss, LOSS_LIMITS);
+    let signal = rtt_signal.max(jitter_signal).max(loss_signal);
+
+    // 決まりを超えたものを知らせる
+    let warnings: Vec<&str> = [
+        (rtt_signal, "high ping"),
+        (jitter_signal, "unstable"),
+        (loss_signal, "packet loss"),
+    ]
+    .into_iter()
+    .filter(|(signal, _)| *signal != Signal::Good)
+    .map(|(_, warning)| warning)
+    .collect();
+
+    for mut text in &mut text_query {
+        text.sections[0].value = signal.bars().to_string();
+        text.sections[0].style.color = signal.color();
+        text.sections[1].value = format!(
+            " ping: {:.0}ms  jitter: {:.0}ms  loss: {:.0}%",
+            rtt * 1000.,
+            quality.jitter * 1000.,
+            loss * 100.
+        );
+        text.sections[2].value = if warnings.is_empty() {
+            String::new()
+        } else {
+            format!("  ! {}", warnings.join(", "))
+        };
+        text.sections[2].style.color = signal.color();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn loss_counts_timed_out_pings() {
+        let start = Instant::now();
+        let mut quality = Quality::default();
+        let ids: Vec<u32> = (0..4).map(|_| quality.ping(start)).collect();
+        quality.pong(ids[0], start + 50 * MS);
+        quality.pong(ids[1], start + 60 * MS);
+
+        // 返事を待っている間は数えない
+        assert_eq!(quality.loss(start + 100 * MS), 0.);
+        // 時間が過ぎたら届かなかったことにする
+        assert_eq!(quality.loss(start + PONG_TIMEOUT + MS), 0.5);
+    }
+
+    #[test]
+    fn late_pong_is_lost() {
+        let start = Instant::now();
+        let mut quality = Quality::default();
+        let id = quality.ping(start);
+        quality.pong(id, start + PONG_TIMEOUT + MS);
+
+        assert_eq!(quality.rtt, None);
+        assert_eq!(quality.loss(start + PONG_TIMEOUT + MS), 1.);
+    }
+
+    #[test]
+    fn jitter_follows_rtt_changes() {
+        let start = Instant::now();
+        let mut quality = Quality::default();
+        for rtt in [100, 120, 80, 80, 200] {
+            let id = quality.ping(start);
+            quality.pong(id, start + rtt * MS);
+        }
+
+        // 差の 20, 40, 0, 120ms を1/16ずつ均したもの
+        assert!(
+            (quality.jitter - 0.010_727_234).abs() < 1e-9,
+            "{}",
+            quality.jitter
+        );
+        assert_eq!(quality.rtt, Some(0.2));
+    }
+}
@@ -0,0 +1,228 @@
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use bevy::time::common_conditions::on_timer;
+
+use super::{
+    move_opponent_attack, to_pos, vs_player_setup, Banner, Game, InfoSection, InfoUpdate, My,
+    MyAttack, Opponent, OpponentAttack, VSState, WeaponText, HEARTBEAT_INTERVAL,
+};
+use crate::entity::{move_player_attack, AttackMethod, PlayerAttackBundle};
+use crate::game::*;
+use crate::menu::MenuState;
+use crate::setting::Setting;
+use crate::{MainState, TextureResource};
+
+// 対戦が終わってからメニューに戻るまで
+const END_DELAY: Duration = Duration::from_secs(3);
+
+/// ホストから届く対戦の様子を映す (入力は送らない)
+/// 自分の位置にホスト、相手の位置にゲストを出す
+pub(super) struct SpectatePlugin;
+
+impl Plugin for SpectatePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            OnEnter(GameMode::VS),
+            (
+                spectate_setup
+                    .after(vs_player_setup)
+                    .run_if(resource_exists::<Spectator>()),
+                accept_spectators
+                    .run_if(resource_exists::<Server>())
+                    .run_if(not(resource_exists::<Spectator>())),
+            ),
+        )
+        .add_systems(OnExit(GameMode::VS), spectate_exit)
+        .add_systems(
+            Update,
+            (
+                watch_send.run_if(on_timer(HEARTBEAT_INTERVAL)),
+                watch_move,
+                watch_attack,
+                watch_hp,
+                watch_end,
+                watch_exit,
+                spectator_hud,
+                move_player_attack::<MyAttack>,
+                move_opponent_attack,
+            )
+                .run_if(in_state(VSState::Watching)),
+        );
+    }
+}
+
+#[derive(Resource, Default)]
+struct Watching {
+    // ホストの位置
+    host: SnapshotBuffer,
+    // 対戦が終わった時刻
+    ended: Option<Instant>,
+}
+
+fn spectate_setup(mut commands: Commands, mut vs_state: ResMut<NextState<VSState>>) {
+    commands.init_resource::<Watching>();
+    vs_state.set(VSState::Watching);
+}
+
+// ホストは観戦者にも対戦の様子を送る
+fn accept_spectators(mut server: ResMut<Server>, role: Res<Role>) {
+    if *role == Role::Host {
+        server.accept_spectators();
+    }
+}
+
+fn spectate_exit(mut commands: Commands) {
+    commands.remove_resource::<Spectator>();
+    commands.remove_resource::<Watching>();
+}
+
+// 観戦を続けていることをホストに知らせる
+fn watch_send(mut server: ResMut<Server>) {
+    server.send(NetMessage::Watch);
+}
+
+type GuestEntity = (With<Opponent>, Without<My>);
+
+fn watch_move(
+    mut event: EventReader<NetMessage>,
+    mut watching: ResMut<Watching>,
+    mut host_query: Query<&mut Transform, With<My>>,
+    mut guest_query: Query<(&mut Transform, &mut SnapshotBuffer), GuestEntity>,
+    time: Res<Time>,
+    setting: Res<Setting>,
+) {
+    let (mut guest_transform, mut guest) = guest_query.single_mut();
+    let now = time.elapsed_seconds_f64();
+
+    for message in event.read() {
+        let NetMessage::Spectate {
+            guest: from_guest,
+            message,
+        } = message
+        else {
+            continue;
+        };
+        if let NetMessage::Position { x, y, time: sent } = **message {
+            if *from_guest {
+                guest.push(sent, now, to_pos(x, y).truncate());
+            } else {
+                watching.host.push(sent, now, Vec2::new(x, y));
+            }
+        }
+    }
+
+    if let Some(position) = watching.host.sample(now, setting.interpolation_delay) {
+        host_query.single_mut().translation = position.extend(0.0);
+    }
+    if let Some(position) = guest.sample(now, setting.interpolation_delay) {
+        guest_transform.translation = position.extend(0.0);
+    }
+}
+
+fn watch_attack(
+    mut commands: Commands,
+    mut event: EventReader<NetMessage>,
+    texture: Res<TextureResource>,
+) {
+    for message in event.read() {
+        let NetMessage::Spectate { guest, message } = message else {
+            continue;
+        };
+        let NetMessage::Fire { x, y, attack } = **message else {
+            continue;
+        };
+
+        for attack_type in attack.list() {
+            let texture = texture.player_attack.clone();
+            if *guest {
+                commands.spawn(PlayerAttackBundle::new(
+                    OpponentAttack::new(attack_type),
+                    texture,
+                    to_pos(x, y),
+                ));
+            } else {
+                commands.spawn(PlayerAttackBundle::new(
+                    MyAttack::new(attack_type),
+                    texture,
+                    Vec3::new(x, y, 0.0),
+                ));
+            }
+        }
+    }
+}
+
+// HPはホストが決めたものをそのまま出す
+fn watch_hp(
+    mut event: EventReader<NetMessage>,
+    mut game: ResMut<Game>,
+    mut info_event: EventWriter<InfoUpdate>,
+) {
+    for message in event.read() {
+        if let NetMessage::Spectate { message, .. } = message {
+            if let NetMessage::HpState { host, guest, .. } = **message {
+                game.my.hp = host;
+                game.opponent.hp = guest;
+                info_event.send(game.info());
+            }
+        }
+    }
+}
+
+// どちらかがやめるか、ホストから届かなくなったら終わり
+fn watch_end(
+    mut event: EventReader<NetMessage>,
+    mut watching: ResMut<Watching>,
+    server: Res<Server>,
+    setting: Res<Setting>,
+    mut banner_query: Query<(&mut Text, &mut Visibility), With<Banner>>,
+) {
+    let left = event.read().any(|message| match message {
+        NetMessage::Spectate { message, .. } => **message == NetMessage::Leave,
+        _ => false,
+    });
+    let lost =
+        server.silence().as_secs_f64() >= setting.disconnect_timeout + setting.reconnect_grace;
+
+    if watching.ended.is_none() && (left || lost) {
+        watching.ended = Some(Instant::now());
+
+        let (mut text, mut visibility) = banner_query.single_mut();
+        text.sections[0].value = "Match ended".to_string();
+        *visibility = Visibility::Visible;
+    }
+}
+
+// Escか、終わってしばらくしたらメニューに戻る
+fn watch_exit(
+    key: Res<Input<KeyCode>>,
+    watching: Res<Watching>,
+    mut game_mode: ResMut<NextState<GameMode>>,
+    mut main_state: ResMut<NextState<MainState>>,
+    mut menu_state: ResMut<NextState<MenuState>>,
+) {
+    if key.just_pressed(KeyCode::Escape) || watching.ended.is_some_and(|t| t.elapsed() >= END_DELAY)
+    {
+        game_mode.set(GameMode::Disabled);
+        main_state.set(MainState::Menu);
+        menu_state.set(MenuState::Main);
+    }
+}
+
+// HPの表示を2人の名前にして、攻撃の種類は出さない
+fn spectator_hud(
+    spectator: Res<Spectator>,
+    mut info_query: Query<(&mut Text, &InfoSection), Added<InfoSection>>,
+    mut weapon_query: Query<&mut Visibility, Added<WeaponText>>,
+) {
+    for (mut text, section) in &mut info_query {
+        let name = match section {
+            InfoSection::My => &spectator.host,
+            InfoSection::Op => &spectator.guest,
+        };
+        text.sections[0].value = format!("{}: ", name);
+    }
+    for mut visibility in &mut weapon_query {
+        *visibility = Visibility::Hidden;
+    }
+}
@@ -5,6 +5,7 @@ mod entity;
 mod game;
 mod game_mode;
 mod menu;
+mod setting;
 
 pub const WINDOW_WIDTH: f32 = 700.0;
 pub const WINDOW_HEIGHT: f32 = 1050.0;
@@ -38,6 +39,7 @@ fn main() {
             }),
             ..default()
         }))
+        .insert_resource(setting::Setting::load())
         .add_state::<MainState>()
         .add_event::<SoundEvent>()
         .add_systems(Startup, setup)
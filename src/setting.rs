@@ -0,0 +1,342 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::{env, fs};
+
+use bevy::prelude::*;
+use local_ip_address::{local_ip, local_ipv6};
+use serde::{Deserialize, Serialize};
+
+use crate::game::{Rules, Weapon, MAX_TICK_RATE};
+
+// 設定を保存するファイル
+const SETTING_FILE: &str = "setting.json";
+// 部屋サーバーのアドレスを指定する環境変数とオプション
+const SERVER_ENV: &str = "INVADER_SERVER";
+const SERVER_FLAG: &str = "--server";
+// 相手に知らせる自分のアドレスを指定するオプション
+const IP_FLAG: &str = "--ip";
+
+const DEFAULT_SERVER: &str = "http://127.0.0.1:9999";
+const DEFAULT_INTERPOLATION_DELAY: f64 = 0.1;
+const DEFAULT_DISCONNECT_TIMEOUT: f64 = 3.0;
+const DEFAULT_RECONNECT_GRACE: f64 = 10.0;
+
+/// 部屋サーバーを通して送るか
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayMode {
+    /// 直接届かなければ切り替える
+    #[default]
+    Auto,
+    /// 最初から中継する
+    Always,
+    /// 中継しない
+    Off,
+}
+
+/// 接続画面で選べるルール
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RuleSet {
+    pub name: String,
+    #[serde(flatten)]
+    pub rules: Rules,
+}
+
+impl RuleSet {
+    fn new(name: &str, rules: Rules) -> RuleSet {
+        RuleSet {
+            name: name.to_string(),
+            rules,
+        }
+    }
+}
+
+fn default_rule_sets() -> Vec<RuleSet> {
+    vec![
+        RuleSet::new("Standard", Rules::default()),
+        RuleSet::new(
+            "Quick",
+            Rules {
+                hp: 25,
+                speed: 350.,
+                time_limit: Some(60),
+                ..default()
+            },
+        ),
+        RuleSet::new(
+            "Power only",
+            Rules {
+                weapons: vec![Weapon::Power],
+                time_limit: Some(90),
+                ..default()
+            },
+        ),
+        RuleSet::new(
+            "Best of 3",
+            Rules {
+                time_limit: Some(90),
+                best_of: 3,
+                ..default()
+            },
+        ),
+    ]
+}
+
+/// 起動時に読み込む設定
+///
+/// 優先順位はコマンドライン引数 > 環境変数 > 設定ファイル > デフォルト値
+#[derive(Resource, Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Setting {
+    /// 部屋サーバーのURL (例: `http://127.0.0.1:9999`)
+    pub server: String,
+    /// 相手の座標を何秒遅らせて補間するか
+    pub interpolation_delay: f64,
+    /// 相手から何も届かなくなってから切断とみなすまでの秒数
+    pub disconnect_timeout: f64,
+    /// 切断とみなしてから不戦勝にするまでの秒数
+    ///
+    /// この間に相手から届き始めれば続ける (相手がつなぎ直して通し番号が初めからになっても受け取る)
+    pub reconnect_grace: f64,
+    /// 相手に知らせる自分のアドレス (なければ自動で探す)
+    ///
+    /// 同じPCで2つ起動して試すときは `127.0.0.1` にする
+    pub ip: Option<IpAddr>,
+    /// 相手に直接届かないとき (NATの内側など) に部屋サーバーを通すか
+    pub relay: RelayMode,
+    /// 相手から受け取る座標や状態の一番多い回数 (1秒あたり)。回線が細ければ下げる
+    pub max_tick_rate: u32,
+    /// VSの部屋を作るときに選べるルール (書き足せば再コンパイルせずに増やせる)
+    pub rule_sets: Vec<RuleSet>,
+}
+
+impl Default for Setting {
+    fn default() -> Self {
+        Setting {
+            server: DEFAULT_SERVER.to_string(),
+            interpolation_delay: DEFAULT_INTERPOLATION_DELAY,
+            disconnect_timeout: DEFAULT_DISCONNECT_TIMEOUT,
+            reconnect_grace: DEFAULT_RECONNECT_GRACE,
+            ip: None,
+            relay: RelayMode::default(),
+            max_tick_rate: MAX_TICK_RATE,
+            rule_sets: default_rule_sets(),
+        }
+    }
+}
+
+impl Setting {
+    pub fn load() -> Setting {
+        Setting::from_sources(
+            fs::read_to_string(SETTING_FILE).ok(),
+            env::var(SERVER_ENV).ok(),
+            env::args().collect(),
+        )
+    }
+
+    // ファイル、環境変数、オプションの順に上書きする
+    fn from_sources(
+        file: Option<String>,
+        server_env: Option<String>,
+        args: Vec<String>,
+    ) -> Setting {
+        let mut setting: Setting = file
+            .and_then(|str| match serde_json::from_str(&str) {
+                Ok(setting) => Some(setting),
+                Err(e) => {
+                    eprintln!("{}: {}", SETTING_FILE, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        if let Some(server) = server_env {
+            setting.set_server(&server);
+        }
+        if let Some(server) = arg_value(args.iter().cloned(), SERVER_FLAG) {
+            setting.set_server(&server);
+        }
+        if let Some(ip) = arg_value(args.into_iter(), IP_FLAG) {
+            match ip.parse() {
+                Ok(ip) => setting.ip = Some(ip),
+                Err(e) => eprintln!("{} {}: {}", IP_FLAG, ip, e),
+            }
+        }
+
+        setting
+    }
+
+    pub fn save(&self) {
+        let result = serde_json::to_string_pretty(self)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(SETTING_FILE, json).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("{}: {}", SETTING_FILE, e);
+        }
+    }
+
+    /// 自分のアドレス (IPv4、IPv6の順に探し、見つからなければループバック)
+    pub fn local_ip(&self) -> IpAddr {
+        self.ip.unwrap_or_else(|| {
+            local_ip().or_else(|_| local_ipv6()).unwrap_or_else(|e| {
+                eprintln!("local ip: {}", e);
+                IpAddr::V4(Ipv4Addr::LOCALHOST)
+            })
+        })
+    }
+
+    /// 中継に使う部屋サーバーのアドレス (URLと同じホストとポートのUDP)
+    pub fn relay_addr(&self) -> Option<SocketAddr> {
+        let host = self
+            .server
+            .split_once("://")
+            .map_or(self.server.as_str(), |(_, rest)| rest);
+        let host = host.split('/').next()?;
+
+        // IPv6のアドレスは `[::1]:9999` のように括弧で囲まれている
+        let has_port = match host.rsplit_once(']') {
+            Some((_, rest)) => rest.starts_with(':'),
+            None => host.contains(':'),
+        };
+        let result = if has_port {
+            host.to_socket_addrs()
+        } else {
+            (host.trim_start_matches('[').trim_end_matches(']'), 80).to_socket_addrs()
+        };
+        match result {
+            Ok(mut addrs) => addrs.next(),
+            Err(e) => {
+                eprintln!("relay {}: {}", host, e);
+                None
+            }
+        }
+    }
+
+    // スキームがなければ http を付ける
+    pub fn set_server(&mut self, server: &str) {
+        let server = server.trim().trim_end_matches('/');
+        if server.is_empty() {
+            return;
+        }
+        self.server = if server.contains("://") {
+            server.to_string()
+        } else {
+            format!("http://{}", server)
+        };
+    }
+}
+
+// `--flag <value>` か `--flag=<value>`
+fn arg_value(mut args: impl Iterator<Item = String>, flag: &str) -> Option<String> {
+    let mut value = None;
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            value = args.next();
+        } else if let Some(v) = arg.strip_prefix(flag).and_then(|a| a.strip_prefix('=')) {
+            value = Some(v.to_string());
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn server(server: &str) -> Setting {
+        Setting {
+            server: server.to_string(),
+            ..Setting::default()
+        }
+    }
+
+    #[test]
+    fn server_precedence() {
+        let file = Some(r#"{ "server": "http://file:1" }"#.to_string());
+        let env = || Some("env:2".to_string());
+        let cli = || args(&["invader-game", "--server", "cli:3"]);
+
+        // オプション > 環境変数 > ファイル > 初めの値
+        let setting = Setting::from_sources(file.clone(), env(), cli());
+        assert_eq!(setting.server, "http://cli:3");
+        let setting = Setting::from_sources(file.clone(), env(), args(&["invader-game"]));
+        assert_eq!(setting.server, "http://env:2");
+        let setting = Setting::from_sources(file, None, args(&["invader-game"]));
+        assert_eq!(setting.server, "http://file:1");
+        let setting = Setting::from_sources(None, None, args(&["invader-game"]));
+        assert_eq!(setting.server, DEFAULT_SERVER);
+
+        // `=` で書いても同じ
+        let setting = Setting::from_sources(None, env(), args(&["invader-game", "--server=cli:3"]));
+        assert_eq!(setting.server, "http://cli:3");
+    }
+
+    #[test]
+    fn missing_or_empty_server_keeps_previous() {
+        // 値のない `--server` と空の値は無視する
+        let setting = Setting::from_sources(
+            None,
+            Some("env:2".to_string()),
+            args(&["invader-game", "--server"]),
+        );
+        assert_eq!(setting.server, "http://env:2");
+        let setting = Setting::from_sources(
+            None,
+            Some("env:2".to_string()),
+            args(&["invader-game", "--server="]),
+        );
+        assert_eq!(setting.server, "http://env:2");
+        let setting = Setting::from_sources(None, Some(" ".to_string()), args(&["invader-game"]));
+        assert_eq!(setting.server, DEFAULT_SERVER);
+        assert_eq!(
+            arg_value(args(&["--server"]).into_iter(), SERVER_FLAG),
+            None
+        );
+        assert_eq!(
+            arg_value(args(&["--ip", "::1"]).into_iter(), SERVER_FLAG),
+            None
+        );
+    }
+
+    #[test]
+    fn set_server_adds_scheme() {
+        let mut setting = Setting::default();
+        setting.set_server("example.com:9999/");
+        assert_eq!(setting.server, "http://example.com:9999");
+        setting.set_server("https://example.com");
+        assert_eq!(setting.server, "https://example.com");
+    }
+
+    #[test]
+    fn relay_addr_with_and_without_port() {
+        let v4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert_eq!(
+            server("http://127.0.0.1:9999").relay_addr(),
+            Some(SocketAddr::new(v4, 9999))
+        );
+        assert_eq!(
+            server("http://[::1]:9999/rooms").relay_addr(),
+            Some(SocketAddr::new(v6, 9999))
+        );
+
+        // ポートがなければ80
+        assert_eq!(
+            server("http://127.0.0.1").relay_addr(),
+            Some(SocketAddr::new(v4, 80))
+        );
+        assert_eq!(
+            server("http://[::1]/").relay_addr(),
+            Some(SocketAddr::new(v6, 80))
+        );
+        assert_eq!(
+            server("127.0.0.1").relay_addr(),
+            Some(SocketAddr::new(v4, 80))
+        );
+    }
+}